@@ -0,0 +1,303 @@
+//! Derives for `maelstrom-node`. Use them through its re-exports rather than directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Field, Fields, Ident, LitStr, Result};
+
+/// Implements `MessageType` for an internally tagged payload enum, naming each variant the way
+/// serde does and pairing every `Foo` with a `FooOk` variant if there is one.
+#[proc_macro_derive(MessageType, attributes(serde))]
+pub fn derive_message_type(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    message_type(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// Makes an enum a Maelstrom payload: derives `Serialize`, `Deserialize` and `MessageType` for
+/// it and tags it with `#[serde(tag = "type", rename_all = "snake_case")]`, so each variant
+/// travels as the `type` named after it.
+#[proc_macro_attribute]
+pub fn payload(args: TokenStream, input: TokenStream) -> TokenStream {
+    let args = proc_macro2::TokenStream::from(args);
+    if !args.is_empty() {
+        return Error::new_spanned(args, "payload takes no arguments")
+            .into_compile_error()
+            .into();
+    }
+    let input = parse_macro_input!(input as DeriveInput);
+    payload_enum(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// Implements `Identity` from a struct's node id and msg id counter fields: the ones marked
+/// `#[node_id]` and `#[msg_id]`, or else the ones named `node_id` or `id`, and `msg_id`.
+#[proc_macro_derive(Identity, attributes(node_id, msg_id))]
+pub fn derive_identity(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    identity(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// Implements `TypedBody` for a message body struct, whose wire `type` is its name in
+/// snake_case unless `#[serde(rename = "...")]` says otherwise.
+#[proc_macro_derive(TypedBody, attributes(serde))]
+pub fn derive_typed_body(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    typed_body(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn payload_enum(input: &DeriveInput) -> Result<proc_macro2::TokenStream> {
+    if !matches!(input.data, Data::Enum(_)) {
+        return Err(Error::new_spanned(
+            input,
+            "payload can only be applied to enums",
+        ));
+    }
+    Ok(quote! {
+        #[derive(::serde::Serialize, ::serde::Deserialize, ::maelstrom_node::MessageType)]
+        #[serde(tag = "type", rename_all = "snake_case")]
+        #input
+    })
+}
+
+fn message_type(input: &DeriveInput) -> Result<proc_macro2::TokenStream> {
+    let Data::Enum(data) = &input.data else {
+        return Err(Error::new_spanned(
+            input,
+            "MessageType can only be derived for enums",
+        ));
+    };
+    let rename_all = serde_value(&input.attrs, "rename_all")?;
+    let mut variants = Vec::new();
+    for variant in &data.variants {
+        let wire = match serde_value(&variant.attrs, "rename")? {
+            Some(name) => name,
+            None => {
+                let name = match rename_all.as_ref() {
+                    None => variant.ident.to_string(),
+                    Some(rule) if rule.value() == "snake_case" => snake_case(&variant.ident),
+                    Some(rule) => {
+                        return Err(Error::new_spanned(
+                            rule,
+                            "MessageType only supports rename_all = \"snake_case\"",
+                        ))
+                    }
+                };
+                LitStr::new(&name, Span::call_site())
+            }
+        };
+        variants.push((&variant.ident, wire));
+    }
+
+    let arms = variants.iter().map(|(ident, wire)| {
+        quote! { Self::#ident { .. } => #wire, }
+    });
+    let pairs = variants.iter().filter_map(|(ident, request)| {
+        let paired = format!("{ident}Ok");
+        let (_, response) = variants
+            .iter()
+            .find(|(other, _)| *other == paired.as_str())?;
+        Some(quote! { #request => Some(#response), })
+    });
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::maelstrom_node::MessageType for #name #ty_generics #where_clause {
+            fn message_type(&self) -> &'static str {
+                match self {
+                    #(#arms)*
+                }
+            }
+
+            fn response_type_for(request_type: &str) -> Option<&'static str> {
+                match request_type {
+                    #(#pairs)*
+                    _ => None,
+                }
+            }
+        }
+    })
+}
+
+fn typed_body(input: &DeriveInput) -> Result<proc_macro2::TokenStream> {
+    if !matches!(input.data, Data::Struct(_)) {
+        return Err(Error::new_spanned(
+            input,
+            "TypedBody can only be derived for structs",
+        ));
+    }
+    let wire = match serde_value(&input.attrs, "rename")? {
+        Some(name) => name,
+        None => LitStr::new(&snake_case(&input.ident), Span::call_site()),
+    };
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::maelstrom_node::TypedBody for #name #ty_generics #where_clause {
+            const TYPE: &'static str = #wire;
+        }
+    })
+}
+
+fn identity(input: &DeriveInput) -> Result<proc_macro2::TokenStream> {
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new_spanned(
+            input,
+            "Identity can only be derived for structs",
+        ));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(Error::new_spanned(
+            input,
+            "Identity needs a struct with named fields",
+        ));
+    };
+    let fields: Vec<&Field> = fields.named.iter().collect();
+    let node_id = find_field(&fields, "node_id", &["node_id", "id"])
+        .ok_or_else(|| Error::new_spanned(input, "no `#[node_id]`, `node_id` or `id` field"))?;
+    let msg_id = find_field(&fields, "msg_id", &["msg_id"])
+        .ok_or_else(|| Error::new_spanned(input, "no `#[msg_id]` or `msg_id` field"))?;
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::maelstrom_node::Identity for #name #ty_generics #where_clause {
+            fn next_msg_id(&mut self) -> usize {
+                let out = self.#msg_id;
+                self.#msg_id += 1;
+                out
+            }
+
+            fn node_id(&self) -> String {
+                self.#node_id.clone()
+            }
+        }
+    })
+}
+
+/// The field carrying `#[marker]`, or failing that the first one named in `names`.
+fn find_field<'a>(fields: &[&'a Field], marker: &str, names: &[&str]) -> Option<&'a Ident> {
+    let marked = fields
+        .iter()
+        .find(|field| field.attrs.iter().any(|attr| attr.path().is_ident(marker)));
+    let named = || {
+        names.iter().find_map(|name| {
+            fields
+                .iter()
+                .find(|field| field.ident.as_ref().is_some_and(|ident| ident == name))
+        })
+    };
+    marked.or_else(named)?.ident.as_ref()
+}
+
+/// The string given for `key` in a `#[serde(...)]` attribute, such as `rename = "id"`.
+fn serde_value(attrs: &[syn::Attribute], key: &str) -> Result<Option<LitStr>> {
+    let mut value = None;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("serde")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident(key) {
+                value = Some(meta.value()?.parse()?);
+            } else if meta.input.peek(syn::Token![=]) {
+                // Skip the values of keys this derive doesn't care about, such as `tag`.
+                meta.value()?.parse::<syn::Expr>()?;
+            } else if meta.input.peek(syn::token::Paren) {
+                let _skipped;
+                syn::parenthesized!(_skipped in meta.input);
+            }
+            Ok(())
+        })?;
+    }
+    Ok(value)
+}
+
+fn snake_case(ident: &Ident) -> String {
+    let mut out = String::new();
+    for (i, c) in ident.to_string().chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                out.push('_');
+            }
+            out.extend(c.to_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use syn::parse_quote;
+
+    use super::*;
+
+    #[test]
+    fn variants_are_named_in_snake_case() {
+        let names = ["Read", "ReadOk", "CommitOffsetsOk"]
+            .map(|name| snake_case(&Ident::new(name, Span::call_site())));
+        assert_eq!(names, ["read", "read_ok", "commit_offsets_ok"]);
+    }
+
+    #[test]
+    fn serde_values_are_found_among_other_keys() {
+        let input: DeriveInput = parse_quote! {
+            #[serde(tag = "type", rename_all = "snake_case")]
+            #[serde(deny_unknown_fields)]
+            enum Payload {}
+        };
+        let rename_all = serde_value(&input.attrs, "rename_all").unwrap();
+        assert_eq!(
+            rename_all.map(|rule| rule.value()).as_deref(),
+            Some("snake_case")
+        );
+        assert!(serde_value(&input.attrs, "rename").unwrap().is_none());
+    }
+
+    #[test]
+    fn unsupported_inputs_are_rejected() {
+        let not_enum: DeriveInput = parse_quote! { struct Payload; };
+        assert!(message_type(&not_enum).is_err());
+        assert!(payload_enum(&not_enum).is_err());
+
+        let camel: DeriveInput = parse_quote! {
+            #[serde(rename_all = "camelCase")]
+            enum Payload { Read {} }
+        };
+        let err = message_type(&camel).unwrap_err();
+        assert!(err.to_string().contains("snake_case"));
+
+        let no_msg_id: DeriveInput = parse_quote! { struct Node { id: String } };
+        assert!(identity(&no_msg_id).is_err());
+
+        let body_enum: DeriveInput = parse_quote! { enum Echo { Echo {} } };
+        assert!(typed_body(&body_enum).is_err());
+    }
+
+    #[test]
+    fn marked_fields_win_over_named_ones() {
+        let input: DeriveInput = parse_quote! {
+            struct Node {
+                id: String,
+                #[node_id]
+                name: String,
+                msg_id: usize,
+            }
+        };
+        let Data::Struct(data) = &input.data else {
+            unreachable!();
+        };
+        let fields: Vec<&Field> = data.fields.iter().collect();
+        let node_id = find_field(&fields, "node_id", &["node_id", "id"]).unwrap();
+        assert_eq!(node_id, "name");
+        let msg_id = find_field(&fields, "msg_id", &["msg_id"]).unwrap();
+        assert_eq!(msg_id, "msg_id");
+    }
+}
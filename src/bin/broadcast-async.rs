@@ -0,0 +1,91 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+    time::Duration,
+};
+
+use anyhow::Result;
+use maelstrom_node::{async_main_loop, payload, AsyncContext, AsyncNode, CallOptions, Init, Message};
+
+#[payload]
+#[derive(Debug, Clone)]
+pub enum Payload {
+    Broadcast {
+        message: usize,
+    },
+    BroadcastOk {},
+    Read {},
+    ReadOk {
+        messages: Vec<usize>,
+    },
+    Topology {
+        topology: HashMap<String, Vec<String>>,
+    },
+    TopologyOk {},
+}
+
+/// The broadcast node of `broadcast.rs` written against `AsyncNode`: delivering a message to a
+/// neighbour is a task that keeps calling it until it answers, instead of a retry policy
+/// attached to a callback.
+struct BroadcastNode {
+    neighbours: RefCell<Vec<String>>,
+    messages: RefCell<HashSet<usize>>,
+}
+
+async fn deliver(ctx: AsyncContext, dest: String, message: usize) -> Result<()> {
+    let options = CallOptions::default().with_timeout(Duration::from_millis(500));
+    loop {
+        let reply = ctx.call_with::<Payload>(
+            dest.clone(),
+            Payload::Broadcast { message },
+            options.clone(),
+        )?;
+        if reply.await.is_ok() {
+            return Ok(());
+        }
+        ctx.sleep(Duration::from_millis(100)).await;
+    }
+}
+
+impl AsyncNode<Payload> for BroadcastNode {
+    fn from_init(init: Init, _ctx: &AsyncContext) -> Result<Self> {
+        let mut neighbours = init.node_ids;
+        neighbours.retain(|id| *id != init.node_id);
+        Ok(Self {
+            neighbours: RefCell::new(neighbours),
+            messages: RefCell::new(HashSet::new()),
+        })
+    }
+
+    async fn handle(self: Rc<Self>, msg: Message<Payload>, ctx: AsyncContext) -> Result<()> {
+        match msg.body.payload.clone() {
+            Payload::Broadcast { message } => {
+                if self.messages.borrow_mut().insert(message) {
+                    for dest in self.neighbours.borrow().iter() {
+                        if *dest != msg.src {
+                            ctx.spawn(deliver(ctx.clone(), dest.clone(), message));
+                        }
+                    }
+                }
+                ctx.reply(&msg, Payload::BroadcastOk {})?;
+            }
+            Payload::Read {} => {
+                let messages = self.messages.borrow().iter().copied().collect();
+                ctx.reply(&msg, Payload::ReadOk { messages })?;
+            }
+            Payload::Topology { topology } => {
+                if let Some(neighbours) = topology.get(ctx.node_id()) {
+                    *self.neighbours.borrow_mut() = neighbours.clone();
+                }
+                ctx.reply(&msg, Payload::TopologyOk {})?;
+            }
+            Payload::BroadcastOk {} | Payload::ReadOk { .. } | Payload::TopologyOk {} => {}
+        }
+        Ok(())
+    }
+}
+
+pub fn main() -> Result<()> {
+    async_main_loop::<BroadcastNode, Payload>()
+}
@@ -1,13 +1,10 @@
-use std::{
-    collections::HashMap,
-    io::StdoutLock,
-    sync::{atomic::AtomicBool, mpsc::Sender},
-    thread::{self, JoinHandle},
-    time::Duration,
-};
+use std::{collections::HashMap, time::Duration};
 
 use anyhow::Result;
-use maelstrom_node::{main_loop, Body, Event, Message, Node};
+use maelstrom_node::{
+    main_loop, payload, CallOptions, Event, Identity, Injector, Message, Node, Output, RetryPolicy,
+    Rpc,
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,9 +28,8 @@ impl Default for Callback {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
+#[payload]
+#[derive(Debug, Clone)]
 pub enum Payload {
     Broadcast {
         message: usize,
@@ -51,88 +47,60 @@ pub enum Payload {
     TopologyOk {},
 }
 
-enum InjectedPayload {
-    CallbackTimeout,
-}
-
+#[derive(Identity)]
 struct BroadcastNode {
     id: String,
     neighbours: Vec<String>,
     msg_id: usize,
     messages: Vec<usize>,
     seen_uids: Vec<String>,
-    callbacks: Vec<(usize, String, usize, String)>,
-    join_handle: Option<JoinHandle<()>>,
+    rpc: Rpc<Self>,
 }
 
 impl BroadcastNode {
     fn broadcast(
         &mut self,
-        msg: Message<Payload>,
         message: usize,
         callback: Callback,
-        output: &mut StdoutLock,
+        output: &mut Output,
     ) -> Result<()> {
         for id in self.neighbours.clone() {
             if callback.nodes.contains(&id) {
                 continue;
             }
-            let msg = msg.clone();
-            let mut msg = self.reply(
-                msg,
+            let msg = self.request(
+                id,
                 Payload::Broadcast {
                     message,
                     callback: callback.clone(),
                 },
             );
-            self.callbacks.push((
-                message,
-                id.clone(),
-                msg.body.id.unwrap(),
-                callback.id().clone(),
-            ));
-            msg.dest = id.clone();
-            msg.send(output)?;
+            let retry = RetryPolicy::exponential(
+                Duration::from_millis(300),
+                Duration::from_secs(5),
+                0.5,
+            );
+            self.rpc.call_with(
+                &msg,
+                &mut *output,
+                CallOptions::default().with_retry(retry),
+                |_: &mut Self, _: Result<Message<Payload>, _>, _| Ok(()),
+            )?;
         }
 
         Ok(())
     }
-
-    pub fn join_thread(&mut self) {
-        let handle = self.join_handle.take();
-        let _ = handle
-            .expect("this function should only be called once")
-            .join(); // FIXME: this
-                     // might be a
-                     // problem
-    }
 }
 
 fn generate_unique_id() -> String {
     ulid::Ulid::new().to_string()
 }
 
-static EOF_FLAG: AtomicBool = AtomicBool::new(false);
-
-impl Node<Payload, InjectedPayload> for BroadcastNode {
-    fn from_init(
-        init: maelstrom_node::Init,
-        tx: Sender<Event<Payload, InjectedPayload>>,
-    ) -> Result<Self>
+impl Node<Payload, ()> for BroadcastNode {
+    fn from_init(init: maelstrom_node::Init, _tx: Injector<()>) -> Result<Self>
     where
         Self: Sized,
     {
-        let join_handle = Some(thread::spawn(move || loop {
-            std::thread::sleep(Duration::from_millis(300));
-            if EOF_FLAG.load(std::sync::atomic::Ordering::Acquire)
-                || tx
-                    .send(Event::Injected(InjectedPayload::CallbackTimeout))
-                    .is_err()
-            {
-                break;
-            }
-        }));
-
         let mut neighbours = init.node_ids;
         neighbours.retain_mut(|id| (*id).ne(&init.node_id));
         Ok(Self {
@@ -141,25 +109,18 @@ impl Node<Payload, InjectedPayload> for BroadcastNode {
             msg_id: 1,
             messages: Vec::new(),
             seen_uids: Vec::new(),
-            callbacks: Vec::new(),
-            join_handle,
+            rpc: Rpc::new(),
         })
     }
 
-    fn next_msg_id(&mut self) -> usize {
-        let out = self.msg_id;
-        self.msg_id += 1;
-        out
-    }
-
-    fn node_id(&self) -> String {
-        self.id.clone()
+    fn rpc(&mut self) -> Option<&mut Rpc<Self>> {
+        Some(&mut self.rpc)
     }
 
     fn process_message(
         &mut self,
-        event: Event<Payload, InjectedPayload>,
-        output: &mut StdoutLock,
+        event: Event<Payload, ()>,
+        output: &mut Output,
     ) -> Result<()> {
         match event {
             Event::Message(msg) => match msg.body.payload.clone() {
@@ -171,7 +132,7 @@ impl Node<Payload, InjectedPayload> for BroadcastNode {
                         callback.nodes.push(self.id.clone());
                         self.messages.push(message);
                         self.seen_uids.push(callback.id().clone());
-                        self.broadcast(msg.clone(), message, callback.clone(), &mut *output)?;
+                        self.broadcast(message, callback.clone(), &mut *output)?;
                     }
                     self.reply(msg, Payload::BroadcastOk {}).send(output)?;
                 }
@@ -190,38 +151,9 @@ impl Node<Payload, InjectedPayload> for BroadcastNode {
                     }
                     self.reply(msg, Payload::TopologyOk {}).send(output)?;
                 }
-                Payload::BroadcastOk {} => self.callbacks.retain(|(_msg, node, msg_id, _uid)| {
-                    msg.src.ne(node)
-                        && !msg
-                            .body
-                            .reply_to
-                            .is_some_and(|reply_id| reply_id == *msg_id)
-                }),
-                Payload::TopologyOk { .. } | Payload::ReadOk { .. } => {}
+                Payload::BroadcastOk {} | Payload::TopologyOk { .. } | Payload::ReadOk { .. } => {}
             },
-            Event::Injected(_) => {
-                let callbacks: Vec<(usize, String, usize, String)> = self.callbacks.drain(..).collect();
-                for (message, node, _original_msg_id, uid) in callbacks {
-                    let callback = Callback {
-                        nodes: vec!(node.clone()),
-                        uid: uid.clone()
-                    };
-                    let msg = Message::new(
-                        self.id.clone(),
-                        node.clone(),
-                        Body::new(
-                            Some(self.next_msg_id()),
-                            Payload::Broadcast { message, callback },
-                        ),
-                    );
-                    msg.send(output)?;
-                    self.callbacks.push((message, node, msg.body.id.unwrap(), uid));
-                }
-            }
-            Event::EOF => {
-                EOF_FLAG.store(true, std::sync::atomic::Ordering::Release);
-                self.join_thread();
-            }
+            Event::Injected(()) | Event::EOF => {}
         }
 
         Ok(())
@@ -229,5 +161,5 @@ impl Node<Payload, InjectedPayload> for BroadcastNode {
 }
 
 pub fn main() -> Result<()> {
-    main_loop::<BroadcastNode, Payload, InjectedPayload>()
+    main_loop::<BroadcastNode, Payload, ()>()
 }
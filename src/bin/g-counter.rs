@@ -0,0 +1,245 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{bail, Result};
+use maelstrom_node::{
+    main_loop, payload, Body, CallOptions, Event, Identity, Injector, Kv, KvError, KvService,
+    Message, Node, Output, Rpc,
+};
+
+const COUNTER_KEY: &str = "counter";
+
+#[payload]
+#[derive(Debug, Clone)]
+pub enum Payload {
+    Add { delta: u64 },
+    AddOk {},
+    Read {},
+    ReadOk { value: u64 },
+    Gossip { counts: HashMap<String, u64> },
+}
+
+#[derive(Clone)]
+enum InjectedPayload {
+    GossipTrigger,
+}
+
+/// How the counter is stored; picked with the `G_COUNTER_MODE` environment variable.
+enum Mode {
+    /// A single key in `seq-kv`, updated with a read/cas loop.
+    SeqKv(Kv),
+    /// A grow-only CRDT: each node owns its own count and gossips the whole map.
+    Crdt,
+}
+
+#[derive(Identity)]
+struct CounterNode {
+    id: String,
+    node_ids: Vec<String>,
+    msg_id: usize,
+    rpc: Rpc<Self>,
+    mode: Mode,
+    counts: HashMap<String, u64>,
+}
+
+impl CounterNode {
+    fn value(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    fn merge(&mut self, counts: HashMap<String, u64>) {
+        for (node, count) in counts {
+            let entry = self.counts.entry(node).or_default();
+            *entry = (*entry).max(count);
+        }
+    }
+
+    fn kv_add(
+        &mut self,
+        kv: Kv,
+        msg: Message<Payload>,
+        delta: u64,
+        output: &mut Output,
+    ) -> Result<()> {
+        kv.clone().read(
+            self,
+            COUNTER_KEY,
+            output,
+            move |node: &mut Self, value: Result<u64, KvError>, output| {
+                let current = match value {
+                    Ok(value) => value,
+                    Err(KvError::KeyDoesNotExist) => 0,
+                    Err(err) => return node.reply_error(msg, err.into()).send(output),
+                };
+                kv.clone().cas_or_create(
+                    node,
+                    COUNTER_KEY,
+                    current,
+                    current + delta,
+                    output,
+                    move |node: &mut Self, result, output| match result {
+                        Ok(()) => node.reply(msg, Payload::AddOk {}).send(output),
+                        Err(KvError::PreconditionFailed) => node.kv_add(kv, msg, delta, output),
+                        Err(err) => node.reply_error(msg, err.into()).send(output),
+                    },
+                )
+            },
+        )
+    }
+
+    /// Reads the counter, then confirms the value with a no-op cas so a stale read from
+    /// `seq-kv` is retried instead of being handed to the client. A missing counter is
+    /// confirmed by creating it as 0, since `seq-kv` may not have seen the first add yet.
+    fn kv_read(&mut self, kv: Kv, msg: Message<Payload>, output: &mut Output) -> Result<()> {
+        kv.clone().read(
+            self,
+            COUNTER_KEY,
+            output,
+            move |node: &mut Self, value: Result<u64, KvError>, output| {
+                let (value, missing) = match value {
+                    Ok(value) => (value, false),
+                    Err(KvError::KeyDoesNotExist) => (0, true),
+                    Err(err) => return node.reply_error(msg, err.into()).send(output),
+                };
+                let confirmed = {
+                    let kv = kv.clone();
+                    move |node: &mut Self, result, output: &mut Output| match result {
+                        Ok(()) => node.reply(msg, Payload::ReadOk { value }).send(output),
+                        Err(KvError::PreconditionFailed) => node.kv_read(kv, msg, output),
+                        Err(err) => node.reply_error(msg, err.into()).send(output),
+                    }
+                };
+                if missing {
+                    kv.cas_or_create(node, COUNTER_KEY, value, value, output, confirmed)
+                } else {
+                    kv.cas(node, COUNTER_KEY, value, value, output, confirmed)
+                }
+            },
+        )
+    }
+}
+
+impl Node<Payload, InjectedPayload> for CounterNode {
+    fn from_init(init: maelstrom_node::Init, tx: Injector<InjectedPayload>) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        let mode = match std::env::var("G_COUNTER_MODE").as_deref() {
+            Ok("seq-kv") => Mode::SeqKv(
+                Kv::new(KvService::SeqKv)
+                    .with_options(CallOptions::default().with_timeout(Duration::from_secs(1))),
+            ),
+            Ok("crdt") | Err(_) => {
+                tx.schedule_every(Duration::from_millis(200), InjectedPayload::GossipTrigger);
+                Mode::Crdt
+            }
+            Ok(other) => bail!("unknown G_COUNTER_MODE {other:?}, expected seq-kv or crdt"),
+        };
+
+        Ok(Self {
+            id: init.node_id,
+            node_ids: init.node_ids,
+            msg_id: 1,
+            rpc: Rpc::new(),
+            mode,
+            counts: HashMap::new(),
+        })
+    }
+
+    fn rpc(&mut self) -> Option<&mut Rpc<Self>> {
+        Some(&mut self.rpc)
+    }
+
+    fn process_message(
+        &mut self,
+        event: Event<Payload, InjectedPayload>,
+        output: &mut Output,
+    ) -> Result<()> {
+        match event {
+            Event::Message(msg) => match (msg.body.payload.clone(), &self.mode) {
+                (Payload::Add { delta }, Mode::SeqKv(kv)) => {
+                    let kv = kv.clone();
+                    self.kv_add(kv, msg, delta, output)?;
+                }
+                (Payload::Add { delta }, Mode::Crdt) => {
+                    *self.counts.entry(self.id.clone()).or_default() += delta;
+                    self.reply(msg, Payload::AddOk {}).send(output)?;
+                }
+                (Payload::Read {}, Mode::SeqKv(kv)) => {
+                    let kv = kv.clone();
+                    self.kv_read(kv, msg, output)?;
+                }
+                (Payload::Read {}, Mode::Crdt) => {
+                    let value = self.value();
+                    self.reply(msg, Payload::ReadOk { value }).send(output)?;
+                }
+                (Payload::Gossip { counts }, _) => self.merge(counts),
+                (Payload::AddOk {} | Payload::ReadOk { .. }, _) => {}
+            },
+            Event::Injected(InjectedPayload::GossipTrigger) => {
+                for node in self.node_ids.clone() {
+                    if node.eq(&self.id) {
+                        continue;
+                    }
+                    let msg = Message::new(
+                        self.id.clone(),
+                        node,
+                        Body::new(
+                            Some(self.next_msg_id()),
+                            Payload::Gossip {
+                                counts: self.counts.clone(),
+                            },
+                        ),
+                    );
+                    msg.send(output)?;
+                }
+            }
+            Event::EOF => {}
+        }
+
+        Ok(())
+    }
+}
+
+pub fn main() -> Result<()> {
+    main_loop::<CounterNode, Payload, InjectedPayload>()
+}
+
+#[cfg(test)]
+mod tests {
+    use maelstrom_node::{check_counter, SimConfig, Simulation};
+
+    use super::*;
+
+    type Sim = Simulation<CounterNode, Payload, InjectedPayload>;
+
+    /// Each node has its own client, whose reads a grow-only counter must never see go down.
+    fn send(sim: &mut Sim, step: u64, payload: Payload) {
+        let (client, node) = (format!("c{}", step % 3 + 1), format!("n{}", step % 3 + 1));
+        sim.send(&client, &node, payload).unwrap();
+        sim.run_for(Duration::from_millis(50)).unwrap();
+    }
+
+    #[test]
+    fn crdt_counters_converge_once_a_partition_heals() {
+        let mut sim = Sim::new(3, SimConfig::default()).unwrap();
+        sim.nemesis_mut().isolate("n1");
+        for step in 0..30 {
+            send(&mut sim, step, Payload::Read {});
+            send(&mut sim, step, Payload::Add { delta: step });
+        }
+        let total = (0..30).sum();
+        assert!(sim.node("n1").unwrap().value() < total);
+
+        sim.nemesis_mut().heal();
+        sim.run_for(Duration::from_secs(1)).unwrap();
+        for step in 0..3 {
+            send(&mut sim, step, Payload::Read {});
+        }
+        sim.run_for(Duration::from_millis(100)).unwrap();
+
+        for id in ["n1", "n2", "n3"] {
+            assert_eq!(sim.node(id).unwrap().value(), total, "{id}");
+        }
+        check_counter(sim.history()).unwrap();
+    }
+}
@@ -1,18 +1,10 @@
-use std::{
-    collections::HashMap,
-    io::StdoutLock,
-    sync::{atomic::AtomicBool, mpsc::Sender},
-    thread::{self, JoinHandle},
-    time::Duration,
-};
+use std::{collections::HashMap, time::Duration};
 
 use anyhow::Result;
-use maelstrom_node::{main_loop, Body, Event, Message, Node};
-use serde::{Deserialize, Serialize};
+use maelstrom_node::{main_loop, payload, Body, Event, Identity, Injector, Message, Node, Output};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
+#[payload]
+#[derive(Debug, Clone)]
 pub enum Payload {
     Broadcast {
         message: usize,
@@ -35,17 +27,18 @@ pub enum Payload {
     },
 }
 
+#[derive(Clone)]
 enum InjectedPayload {
     GossipTrigger,
 }
 
+#[derive(Identity)]
 struct BroadcastNode {
     id: String,
     neighbours: Vec<String>,
     msg_id: usize,
     messages: Vec<usize>,
     has_update: Option<String>,
-    join_handle: Option<JoinHandle<()>>,
 }
 
 impl BroadcastNode {
@@ -53,7 +46,7 @@ impl BroadcastNode {
         &mut self,
         msg: Message<Payload>,
         message: usize,
-        output: &mut StdoutLock,
+        output: &mut Output,
     ) -> Result<()> {
         for id in self.neighbours.clone() {
             let msg = msg.clone();
@@ -70,37 +63,17 @@ impl BroadcastNode {
 
         Ok(())
     }
-
-    pub fn join_thread(&mut self) {
-        let handle = self.join_handle.take();
-        let _ = handle
-            .expect("this function should only be called once")
-            .join(); // FIXME: this
-                     // might be a
-                     // problem
-    }
 }
 
-static EOF_FLAG: AtomicBool = AtomicBool::new(false);
-
 impl Node<Payload, InjectedPayload> for BroadcastNode {
     fn from_init(
         init: maelstrom_node::Init,
-        tx: Sender<Event<Payload, InjectedPayload>>,
+        tx: Injector<InjectedPayload>,
     ) -> Result<Self>
     where
         Self: Sized,
     {
-        let join_handle = Some(thread::spawn(move || loop {
-            std::thread::sleep(Duration::from_millis(20));
-            if EOF_FLAG.load(std::sync::atomic::Ordering::Acquire)
-                || tx
-                    .send(Event::Injected(InjectedPayload::GossipTrigger))
-                    .is_err()
-            {
-                break;
-            }
-        }));
+        tx.schedule_every(Duration::from_millis(20), InjectedPayload::GossipTrigger);
 
         let mut neighbours = init.node_ids;
         neighbours.retain_mut(|id| (*id).ne(&init.node_id));
@@ -110,24 +83,13 @@ impl Node<Payload, InjectedPayload> for BroadcastNode {
             msg_id: 1,
             messages: Vec::new(),
             has_update: None,
-            join_handle,
         })
     }
 
-    fn next_msg_id(&mut self) -> usize {
-        let out = self.msg_id;
-        self.msg_id += 1;
-        out
-    }
-
-    fn node_id(&self) -> String {
-        self.id.clone()
-    }
-
     fn process_message(
         &mut self,
         event: Event<Payload, InjectedPayload>,
-        output: &mut StdoutLock,
+        output: &mut Output,
     ) -> Result<()> {
         match event {
             Event::Message(msg) => match msg.body.payload.clone() {
@@ -207,10 +169,7 @@ impl Node<Payload, InjectedPayload> for BroadcastNode {
                     msg.send(output)?;
                 }
             }
-            Event::EOF => {
-                EOF_FLAG.store(true, std::sync::atomic::Ordering::Release);
-                self.join_thread();
-            }
+            Event::EOF => {}
         }
 
         Ok(())
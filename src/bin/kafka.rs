@@ -0,0 +1,425 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use maelstrom_node::{
+    main_loop, payload, CallOptions, Event, Identity, Injector, Kv, KvError, KvService, Message,
+    Node, Output, RetryPolicy, Rpc,
+};
+
+#[payload]
+#[derive(Debug, Clone)]
+pub enum Payload {
+    Send {
+        key: String,
+        msg: u64,
+    },
+    SendOk {
+        offset: u64,
+    },
+    Poll {
+        offsets: HashMap<String, u64>,
+    },
+    PollOk {
+        msgs: HashMap<String, Vec<(u64, u64)>>,
+    },
+    CommitOffsets {
+        offsets: HashMap<String, u64>,
+    },
+    CommitOffsetsOk {},
+    ListCommittedOffsets {
+        keys: Vec<String>,
+    },
+    ListCommittedOffsetsOk {
+        offsets: HashMap<String, u64>,
+    },
+    Replicate {
+        key: String,
+        offset: u64,
+        msg: u64,
+    },
+    ReplicateOk {},
+    ReplicateCommits {
+        offsets: HashMap<String, u64>,
+    },
+    ReplicateCommitsOk {},
+}
+
+/// Where the logs live; picked with the `KAFKA_MODE` environment variable.
+enum Mode {
+    /// Everything in local memory. Only correct when the cluster is a single node.
+    Single,
+    /// Each key's whole log is one value in `lin-kv`, appended to with a read/cas loop.
+    LinKv(Kv),
+    /// Each key is owned by one node, which assigns offsets and replicates to everyone else.
+    Owned,
+}
+
+#[derive(Identity)]
+struct KafkaNode {
+    id: String,
+    node_ids: Vec<String>,
+    msg_id: usize,
+    rpc: Rpc<Self>,
+    mode: Mode,
+    logs: HashMap<String, BTreeMap<u64, u64>>,
+    committed: HashMap<String, u64>,
+}
+
+fn log_key(key: &str) -> String {
+    format!("log/{key}")
+}
+
+fn commit_key(key: &str) -> String {
+    format!("commit/{key}")
+}
+
+fn replication_options() -> CallOptions {
+    CallOptions::default().with_retry(RetryPolicy::exponential(
+        Duration::from_millis(200),
+        Duration::from_secs(2),
+        0.5,
+    ))
+}
+
+impl KafkaNode {
+    fn owner(&self, key: &str) -> &String {
+        let hash: usize = key.bytes().map(usize::from).sum();
+        &self.node_ids[hash % self.node_ids.len()]
+    }
+
+    fn append(&mut self, key: String, msg: u64) -> u64 {
+        let log = self.logs.entry(key).or_default();
+        let offset = log.last_key_value().map_or(0, |(offset, _)| offset + 1);
+        log.insert(offset, msg);
+        offset
+    }
+
+    /// Messages at or after `from`, stopping at the first gap a lagging replica may have.
+    fn read_log(&self, key: &str, from: u64) -> Vec<(u64, u64)> {
+        let Some(log) = self.logs.get(key) else {
+            return Vec::new();
+        };
+        log.range(from..)
+            .zip(from..)
+            .take_while(|((offset, _), expected)| **offset == *expected)
+            .map(|((offset, msg), _)| (*offset, *msg))
+            .collect()
+    }
+
+    fn commit(&mut self, offsets: HashMap<String, u64>) {
+        for (key, offset) in offsets {
+            let committed = self.committed.entry(key).or_default();
+            *committed = (*committed).max(offset);
+        }
+    }
+
+    fn peers(&self) -> Vec<String> {
+        self.node_ids
+            .iter()
+            .filter(|node| node.ne(&&self.id))
+            .cloned()
+            .collect()
+    }
+
+    fn replicate(&mut self, payload: Payload, output: &mut Output) -> Result<()> {
+        for node in self.peers() {
+            let msg = self.request(node, payload.clone());
+            self.rpc.call_with(
+                &msg,
+                &mut *output,
+                replication_options(),
+                |_: &mut Self, _: Result<Message<Payload>, _>, _| Ok(()),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn owned_send(
+        &mut self,
+        msg: Message<Payload>,
+        key: String,
+        value: u64,
+        output: &mut Output,
+    ) -> Result<()> {
+        let owner = self.owner(&key).clone();
+        if owner.eq(&self.id) {
+            let offset = self.append(key.clone(), value);
+            self.replicate(
+                Payload::Replicate {
+                    key,
+                    offset,
+                    msg: value,
+                },
+                &mut *output,
+            )?;
+            return self.reply(msg, Payload::SendOk { offset }).send(output);
+        }
+
+        // Not ours: let the owner assign the offset and relay its answer.
+        let forward = self.request(owner, Payload::Send { key, msg: value });
+        let options = CallOptions::default().with_timeout(Duration::from_secs(1));
+        self.rpc.forward(&forward, msg, output, options)
+    }
+
+    fn kv_send(
+        &mut self,
+        kv: Kv,
+        msg: Message<Payload>,
+        key: String,
+        value: u64,
+        output: &mut Output,
+    ) -> Result<()> {
+        kv.clone().read(
+            self,
+            log_key(&key),
+            output,
+            move |node: &mut Self, log: Result<Vec<u64>, KvError>, output| {
+                let log = match log {
+                    Ok(log) => log,
+                    Err(KvError::KeyDoesNotExist) => Vec::new(),
+                    Err(err) => return node.reply_error(msg, err.into()).send(output),
+                };
+                let offset = log.len() as u64;
+                let mut appended = log.clone();
+                appended.push(value);
+                kv.clone().cas_or_create(
+                    node,
+                    log_key(&key),
+                    log,
+                    appended,
+                    output,
+                    move |node: &mut Self, result, output| match result {
+                        Ok(()) => node.reply(msg, Payload::SendOk { offset }).send(output),
+                        Err(KvError::PreconditionFailed) => {
+                            node.kv_send(kv, msg, key, value, output)
+                        }
+                        Err(err) => node.reply_error(msg, err.into()).send(output),
+                    },
+                )
+            },
+        )
+    }
+
+    /// Reads one key's log per round trip, carrying the collected messages along.
+    fn kv_poll(
+        &mut self,
+        kv: Kv,
+        msg: Message<Payload>,
+        mut offsets: Vec<(String, u64)>,
+        mut msgs: HashMap<String, Vec<(u64, u64)>>,
+        output: &mut Output,
+    ) -> Result<()> {
+        let Some((key, from)) = offsets.pop() else {
+            return self.reply(msg, Payload::PollOk { msgs }).send(output);
+        };
+        kv.clone().read(
+            self,
+            log_key(&key),
+            output,
+            move |node: &mut Self, log: Result<Vec<u64>, KvError>, output| {
+                match log {
+                    Ok(log) => {
+                        let entries = (0..).zip(log).skip(from as usize).collect();
+                        msgs.insert(key, entries);
+                    }
+                    Err(KvError::KeyDoesNotExist) => {}
+                    Err(err) => return node.reply_error(msg, err.into()).send(output),
+                }
+                node.kv_poll(kv, msg, offsets, msgs, output)
+            },
+        )
+    }
+
+    /// Raises each key's committed offset with a read/cas loop, like `kv_send`, so a late
+    /// commit of an older offset never moves it backwards.
+    fn kv_commit(
+        &mut self,
+        kv: Kv,
+        msg: Message<Payload>,
+        mut offsets: Vec<(String, u64)>,
+        output: &mut Output,
+    ) -> Result<()> {
+        let Some((key, offset)) = offsets.pop() else {
+            return self.reply(msg, Payload::CommitOffsetsOk {}).send(output);
+        };
+        kv.clone().read(
+            self,
+            commit_key(&key),
+            output,
+            move |node: &mut Self, committed: Result<u64, KvError>, output| {
+                let committed = match committed {
+                    Ok(committed) if committed >= offset => {
+                        return node.kv_commit(kv, msg, offsets, output);
+                    }
+                    Ok(committed) => Some(committed),
+                    Err(KvError::KeyDoesNotExist) => None,
+                    Err(err) => return node.reply_error(msg, err.into()).send(output),
+                };
+                let raised = {
+                    let (kv, key) = (kv.clone(), key.clone());
+                    move |node: &mut Self, result, output: &mut Output| match result {
+                        Ok(()) => node.kv_commit(kv, msg, offsets, output),
+                        Err(KvError::PreconditionFailed) => {
+                            offsets.push((key, offset));
+                            node.kv_commit(kv, msg, offsets, output)
+                        }
+                        Err(err) => node.reply_error(msg, err.into()).send(output),
+                    }
+                };
+                match committed {
+                    Some(committed) => {
+                        kv.cas(node, commit_key(&key), committed, offset, output, raised)
+                    }
+                    None => {
+                        kv.cas_or_create(node, commit_key(&key), offset, offset, output, raised)
+                    }
+                }
+            },
+        )
+    }
+
+    fn kv_list_committed(
+        &mut self,
+        kv: Kv,
+        msg: Message<Payload>,
+        mut keys: Vec<String>,
+        mut offsets: HashMap<String, u64>,
+        output: &mut Output,
+    ) -> Result<()> {
+        let Some(key) = keys.pop() else {
+            return self
+                .reply(msg, Payload::ListCommittedOffsetsOk { offsets })
+                .send(output);
+        };
+        kv.clone().read(
+            self,
+            commit_key(&key),
+            output,
+            move |node: &mut Self, offset: Result<u64, KvError>, output| {
+                match offset {
+                    Ok(offset) => {
+                        offsets.insert(key, offset);
+                    }
+                    Err(KvError::KeyDoesNotExist) => {}
+                    Err(err) => return node.reply_error(msg, err.into()).send(output),
+                }
+                node.kv_list_committed(kv, msg, keys, offsets, output)
+            },
+        )
+    }
+}
+
+impl Node<Payload, ()> for KafkaNode {
+    fn from_init(init: maelstrom_node::Init, _tx: Injector<()>) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        let mode = match std::env::var("KAFKA_MODE").as_deref() {
+            Ok("single") | Err(_) => Mode::Single,
+            Ok("lin-kv") => Mode::LinKv(
+                Kv::new(KvService::LinKv)
+                    .with_options(CallOptions::default().with_timeout(Duration::from_secs(1))),
+            ),
+            Ok("owned") => Mode::Owned,
+            Ok(other) => bail!("unknown KAFKA_MODE {other:?}, expected single, lin-kv or owned"),
+        };
+
+        Ok(Self {
+            id: init.node_id,
+            node_ids: init.node_ids,
+            msg_id: 1,
+            rpc: Rpc::new(),
+            mode,
+            logs: HashMap::new(),
+            committed: HashMap::new(),
+        })
+    }
+
+    fn rpc(&mut self) -> Option<&mut Rpc<Self>> {
+        Some(&mut self.rpc)
+    }
+
+    fn process_message(&mut self, event: Event<Payload, ()>, output: &mut Output) -> Result<()> {
+        let Event::Message(msg) = event else {
+            return Ok(());
+        };
+
+        let kv = match &self.mode {
+            Mode::LinKv(kv) => Some(kv.clone()),
+            Mode::Single | Mode::Owned => None,
+        };
+        match (msg.body.payload.clone(), kv) {
+            (Payload::Send { key, msg: value }, Some(kv)) => {
+                self.kv_send(kv, msg, key, value, output)?;
+            }
+            (Payload::Send { key, msg: value }, None) => {
+                if matches!(self.mode, Mode::Owned) {
+                    self.owned_send(msg, key, value, output)?;
+                } else {
+                    let offset = self.append(key, value);
+                    self.reply(msg, Payload::SendOk { offset }).send(output)?;
+                }
+            }
+            (Payload::Poll { offsets }, Some(kv)) => {
+                self.kv_poll(kv, msg, offsets.into_iter().collect(), HashMap::new(), output)?;
+            }
+            (Payload::Poll { offsets }, None) => {
+                let msgs = offsets
+                    .into_iter()
+                    .map(|(key, from)| {
+                        let entries = self.read_log(&key, from);
+                        (key, entries)
+                    })
+                    .collect();
+                self.reply(msg, Payload::PollOk { msgs }).send(output)?;
+            }
+            (Payload::CommitOffsets { offsets }, Some(kv)) => {
+                self.kv_commit(kv, msg, offsets.into_iter().collect(), output)?;
+            }
+            (Payload::CommitOffsets { offsets }, None) => {
+                self.commit(offsets.clone());
+                if matches!(self.mode, Mode::Owned) {
+                    self.replicate(Payload::ReplicateCommits { offsets }, &mut *output)?;
+                }
+                self.reply(msg, Payload::CommitOffsetsOk {}).send(output)?;
+            }
+            (Payload::ListCommittedOffsets { keys }, Some(kv)) => {
+                self.kv_list_committed(kv, msg, keys, HashMap::new(), output)?;
+            }
+            (Payload::ListCommittedOffsets { keys }, None) => {
+                let offsets = keys
+                    .into_iter()
+                    .filter_map(|key| Some((key.clone(), *self.committed.get(&key)?)))
+                    .collect();
+                self.reply(msg, Payload::ListCommittedOffsetsOk { offsets })
+                    .send(output)?;
+            }
+            (Payload::Replicate { key, offset, msg: value }, _) => {
+                self.logs.entry(key).or_default().insert(offset, value);
+                self.reply(msg, Payload::ReplicateOk {}).send(output)?;
+            }
+            (Payload::ReplicateCommits { offsets }, _) => {
+                self.commit(offsets);
+                self.reply(msg, Payload::ReplicateCommitsOk {}).send(output)?;
+            }
+            (
+                Payload::SendOk { .. }
+                | Payload::PollOk { .. }
+                | Payload::CommitOffsetsOk {}
+                | Payload::ListCommittedOffsetsOk { .. }
+                | Payload::ReplicateOk {}
+                | Payload::ReplicateCommitsOk {},
+                _,
+            ) => {}
+        }
+
+        Ok(())
+    }
+}
+
+pub fn main() -> Result<()> {
+    main_loop::<KafkaNode, Payload, ()>()
+}
@@ -0,0 +1,420 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::Result;
+use maelstrom_node::{
+    main_loop, payload, AppendEntries, AppendEntriesResult, CallOptions, Error, ErrorCode, Event,
+    Identity, Injector, Message, Node, NotLeader, Outbound, Output, Raft, RaftConfig, RaftRequest,
+    RequestVote, RequestVoteResult, Rpc, RpcError,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Command {
+    Read {
+        key: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+}
+
+#[payload]
+#[derive(Debug, Clone)]
+pub enum Payload {
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    WriteOk {},
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+    CasOk {},
+    RequestVote(RequestVote),
+    RequestVoteOk(RequestVoteResult),
+    AppendEntries(AppendEntries<Command>),
+    AppendEntriesOk(AppendEntriesResult),
+}
+
+#[derive(Clone)]
+enum InjectedPayload {
+    Tick,
+}
+
+/// A linearizable key/value store: every client operation, reads included, is a Raft log
+/// entry, and only the node that proposed an entry answers the client once it is applied.
+/// Followers proxy client requests to the leader they know of.
+#[derive(Identity)]
+struct KvNode {
+    id: String,
+    msg_id: usize,
+    rpc: Rpc<Self>,
+    raft: Raft<Command>,
+    store: HashMap<String, Value>,
+    waiting: HashMap<u64, (u64, Message<Payload>)>,
+}
+
+fn raft_options() -> CallOptions {
+    CallOptions::default().with_timeout(Duration::from_millis(500))
+}
+
+impl KvNode {
+    fn send_raft(&mut self, outbound: Vec<Outbound<Command>>, output: &mut Output) -> Result<()> {
+        for Outbound { dest, request } in outbound {
+            match request {
+                RaftRequest::RequestVote(request) => {
+                    let msg = self.request(dest, Payload::RequestVote(request));
+                    self.rpc.call_with(
+                        &msg,
+                        &mut *output,
+                        raft_options(),
+                        |node: &mut Self, reply: Result<Message<Payload>, RpcError>, output| {
+                            let Ok(Message {
+                                src,
+                                body:
+                                    maelstrom_node::Body {
+                                        payload: Payload::RequestVoteOk(result),
+                                        ..
+                                    },
+                                ..
+                            }) = reply
+                            else {
+                                return Ok(());
+                            };
+                            let now = node.rpc.now();
+                            let outbound = node.raft.handle_request_vote_result(src, result, now);
+                            node.send_raft(outbound, output)?;
+                            node.apply(output)
+                        },
+                    )?;
+                }
+                RaftRequest::AppendEntries(request) => {
+                    let msg = self.request(dest, Payload::AppendEntries(request));
+                    self.rpc.call_with(
+                        &msg,
+                        &mut *output,
+                        raft_options(),
+                        |node: &mut Self, reply: Result<Message<Payload>, RpcError>, output| {
+                            let Ok(Message {
+                                src,
+                                body:
+                                    maelstrom_node::Body {
+                                        payload: Payload::AppendEntriesOk(result),
+                                        ..
+                                    },
+                                ..
+                            }) = reply
+                            else {
+                                return Ok(());
+                            };
+                            let now = node.rpc.now();
+                            node.raft.handle_append_entries_result(src, result, now);
+                            node.apply(output)
+                        },
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, command: Command) -> Result<Payload, Error> {
+        match command {
+            Command::Read { key } => match self.store.get(&key.to_string()) {
+                Some(value) => Ok(Payload::ReadOk {
+                    value: value.clone(),
+                }),
+                None => Err(Error::new(
+                    ErrorCode::KeyDoesNotExist,
+                    format!("no key {key}"),
+                )),
+            },
+            Command::Write { key, value } => {
+                self.store.insert(key.to_string(), value);
+                Ok(Payload::WriteOk {})
+            }
+            Command::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => match self.store.get_mut(&key.to_string()) {
+                Some(value) if *value == from => {
+                    *value = to;
+                    Ok(Payload::CasOk {})
+                }
+                Some(value) => Err(Error::new(
+                    ErrorCode::PreconditionFailed,
+                    format!("expected {from}, had {value}"),
+                )),
+                None if create_if_not_exists => {
+                    self.store.insert(key.to_string(), to);
+                    Ok(Payload::CasOk {})
+                }
+                None => Err(Error::new(
+                    ErrorCode::KeyDoesNotExist,
+                    format!("no key {key}"),
+                )),
+            },
+        }
+    }
+
+    /// Applies newly committed entries and answers the clients waiting on them, then lets go
+    /// of the clients whose entries this node can no longer see through.
+    fn apply(&mut self, output: &mut Output) -> Result<()> {
+        for (index, entry) in self.raft.take_committed() {
+            let result = self.execute(entry.command);
+            let Some((term, msg)) = self.waiting.remove(&index) else {
+                continue;
+            };
+            // A different term means our entry was overwritten by another leader's.
+            let reply = if term != entry.term {
+                Err(Error::new(
+                    ErrorCode::TemporarilyUnavailable,
+                    "lost leadership",
+                ))
+            } else {
+                result
+            };
+            match reply {
+                Ok(payload) => self.reply(msg, payload).send(output)?,
+                Err(error) => self.reply_error(msg, error).send(output)?,
+            }
+        }
+
+        // Waiters only outlive a change of term by outliving this node's leadership, and after
+        // that a later leader either commits their entries or replaces them.
+        if self.raft.is_leader() {
+            return Ok(());
+        }
+        for (index, (term, msg)) in std::mem::take(&mut self.waiting) {
+            let error = if self.raft.term_at(index) == Some(term) {
+                // Still in our log, so it may yet commit.
+                Error::new(
+                    ErrorCode::Timeout,
+                    "lost leadership; the request may still take effect",
+                )
+            } else {
+                Error::new(ErrorCode::TemporarilyUnavailable, "lost leadership")
+            };
+            self.reply_error(msg, error).send(output)?;
+        }
+        Ok(())
+    }
+
+    fn submit(
+        &mut self,
+        msg: Message<Payload>,
+        command: Command,
+        output: &mut Output,
+    ) -> Result<()> {
+        match self.raft.propose(command) {
+            Ok(index) => {
+                let waiter = (self.raft.term(), msg);
+                // Our new entry took that index, so whatever waited there was overwritten.
+                if let Some((_, overwritten)) = self.waiting.insert(index, waiter) {
+                    let error = Error::new(ErrorCode::TemporarilyUnavailable, "lost leadership");
+                    self.reply_error(overwritten, error).send(output)?;
+                }
+                self.apply(output)
+            }
+            Err(NotLeader {
+                leader: Some(leader),
+            }) => self.proxy(msg, leader, output),
+            Err(NotLeader { leader: None }) => self
+                .reply_error(
+                    msg,
+                    Error::new(ErrorCode::TemporarilyUnavailable, "no leader elected"),
+                )
+                .send(output),
+        }
+    }
+
+    fn proxy(&mut self, msg: Message<Payload>, leader: String, output: &mut Output) -> Result<()> {
+        let forward = self.request(leader, msg.body.payload.clone());
+        let options = CallOptions::default().with_timeout(Duration::from_secs(1));
+        self.rpc.forward(&forward, msg, output, options)
+    }
+}
+
+impl Node<Payload, InjectedPayload> for KvNode {
+    fn from_init(init: maelstrom_node::Init, tx: Injector<InjectedPayload>) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        tx.schedule_every(Duration::from_millis(10), InjectedPayload::Tick);
+
+        // Time comes from the loop's clock throughout, so the simulator can drive elections.
+        let rpc = Rpc::new();
+        let raft = Raft::new(
+            init.node_id.clone(),
+            init.node_ids,
+            RaftConfig::default(),
+            rpc.now(),
+        );
+        Ok(Self {
+            id: init.node_id,
+            msg_id: 1,
+            rpc,
+            raft,
+            store: HashMap::new(),
+            waiting: HashMap::new(),
+        })
+    }
+
+    fn rpc(&mut self) -> Option<&mut Rpc<Self>> {
+        Some(&mut self.rpc)
+    }
+
+    fn process_message(
+        &mut self,
+        event: Event<Payload, InjectedPayload>,
+        output: &mut Output,
+    ) -> Result<()> {
+        match event {
+            Event::Message(msg) => match msg.body.payload.clone() {
+                Payload::Read { key } => self.submit(msg, Command::Read { key }, output)?,
+                Payload::Write { key, value } => {
+                    self.submit(msg, Command::Write { key, value }, output)?;
+                }
+                Payload::Cas {
+                    key,
+                    from,
+                    to,
+                    create_if_not_exists,
+                } => {
+                    let command = Command::Cas {
+                        key,
+                        from,
+                        to,
+                        create_if_not_exists,
+                    };
+                    self.submit(msg, command, output)?;
+                }
+                Payload::RequestVote(request) => {
+                    let result = self.raft.handle_request_vote(request, self.rpc.now());
+                    self.reply(msg, Payload::RequestVoteOk(result))
+                        .send(output)?;
+                    self.apply(output)?;
+                }
+                Payload::AppendEntries(request) => {
+                    let result = self.raft.handle_append_entries(request, self.rpc.now());
+                    self.reply(msg, Payload::AppendEntriesOk(result))
+                        .send(output)?;
+                    self.apply(output)?;
+                }
+                Payload::ReadOk { .. }
+                | Payload::WriteOk {}
+                | Payload::CasOk {}
+                | Payload::RequestVoteOk(_)
+                | Payload::AppendEntriesOk(_) => {}
+            },
+            Event::Injected(InjectedPayload::Tick) => {
+                let outbound = self.raft.tick(self.rpc.now());
+                self.send_raft(outbound, output)?;
+                self.apply(output)?;
+            }
+            Event::EOF => {}
+        }
+
+        Ok(())
+    }
+}
+
+pub fn main() -> Result<()> {
+    main_loop::<KvNode, Payload, InjectedPayload>()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+
+    use maelstrom_node::{check_linearizable, Outcome, SimConfig, Simulation};
+    use serde_json::json;
+
+    use super::*;
+
+    type Sim = Simulation<KvNode, Payload, InjectedPayload>;
+
+    /// The nodes that believe they lead; an isolated leader keeps believing it.
+    fn leaders(sim: &Sim) -> Vec<String> {
+        sim.node_ids()
+            .filter(|id| sim.node(id).is_some_and(|node| node.raft.is_leader()))
+            .cloned()
+            .collect()
+    }
+
+    fn op(step: u64) -> Payload {
+        let key = json!(step % 2);
+        match step % 3 {
+            0 => Payload::Read { key },
+            1 => Payload::Write {
+                key,
+                value: json!(step % 4),
+            },
+            _ => Payload::Cas {
+                key,
+                from: json!(step % 4),
+                to: json!((step + 1) % 4),
+                create_if_not_exists: false,
+            },
+        }
+    }
+
+    /// Sends one op per 50ms, to `dest` or else round robin.
+    fn send(sim: &mut Sim, steps: Range<u64>, dest: Option<&str>) {
+        for step in steps {
+            let client = format!("c{}", step % 3);
+            let dest = dest.map_or(format!("n{}", step % 3 + 1), str::to_string);
+            sim.send(&client, &dest, op(step)).unwrap();
+            sim.run_for(Duration::from_millis(50)).unwrap();
+        }
+    }
+
+    #[test]
+    fn stays_linearizable_when_the_leader_is_cut_off() {
+        let mut sim = Sim::new(3, SimConfig::default()).unwrap();
+        sim.run_for(Duration::from_secs(1)).unwrap();
+        let [first] = <[String; 1]>::try_from(leaders(&sim)).expect("one leader after a second");
+
+        send(&mut sim, 0..20, None);
+        // The cut-off leader accepts entries that the rest of the cluster never sees.
+        sim.nemesis_mut().isolate(&first);
+        send(&mut sim, 20..30, Some(&first));
+        while leaders(&sim) == [first.clone()] {
+            sim.run_for(Duration::from_millis(100)).unwrap();
+        }
+        sim.nemesis_mut().heal();
+        sim.run_for(Duration::from_secs(1)).unwrap();
+        assert!(!sim.node(&first).unwrap().raft.is_leader());
+        assert!(sim.node(&first).unwrap().waiting.is_empty());
+        send(&mut sim, 30..50, None);
+        sim.run_for(Duration::from_secs(2)).unwrap();
+
+        let ops = sim.history().operations();
+        assert!(ops.iter().any(|op| op.outcome == Outcome::Ok));
+        assert!(ops.iter().all(|op| op.completed.is_some()));
+        check_linearizable(sim.history()).unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::Result;
+use maelstrom_node::{main_loop, payload, Body, Event, Identity, Injector, Message, Node, Output};
+
+#[payload]
+#[derive(Debug, Clone)]
+pub enum Payload {
+    Add {
+        delta: i64,
+    },
+    AddOk {},
+    Read {},
+    ReadOk {
+        value: i64,
+    },
+    Gossip {
+        increments: HashMap<String, u64>,
+        decrements: HashMap<String, u64>,
+    },
+}
+
+#[derive(Clone)]
+enum InjectedPayload {
+    GossipTrigger,
+}
+
+/// A PN-counter is two grow-only counters: `increments` (P) collects positive deltas and
+/// `decrements` (N) the magnitude of negative ones, each keyed by the node that accepted the
+/// `add`. A node only ever bumps its own entries, so merging two replicas is an entry-wise max
+/// and the value is `sum(P) - sum(N)`.
+#[derive(Identity)]
+struct CounterNode {
+    id: String,
+    neighbours: Vec<String>,
+    msg_id: usize,
+    increments: HashMap<String, u64>,
+    decrements: HashMap<String, u64>,
+}
+
+impl CounterNode {
+    fn add(&mut self, delta: i64) {
+        let counts = if delta >= 0 {
+            &mut self.increments
+        } else {
+            &mut self.decrements
+        };
+        *counts.entry(self.id.clone()).or_default() += delta.unsigned_abs();
+    }
+
+    fn value(&self) -> i64 {
+        let increments: u64 = self.increments.values().sum();
+        let decrements: u64 = self.decrements.values().sum();
+        increments as i64 - decrements as i64
+    }
+
+    fn merge(ours: &mut HashMap<String, u64>, theirs: HashMap<String, u64>) {
+        for (node, count) in theirs {
+            let entry = ours.entry(node).or_default();
+            *entry = (*entry).max(count);
+        }
+    }
+}
+
+impl Node<Payload, InjectedPayload> for CounterNode {
+    fn from_init(init: maelstrom_node::Init, tx: Injector<InjectedPayload>) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        tx.schedule_every(Duration::from_millis(200), InjectedPayload::GossipTrigger);
+
+        let mut neighbours = init.node_ids;
+        neighbours.retain_mut(|id| (*id).ne(&init.node_id));
+        Ok(Self {
+            id: init.node_id,
+            neighbours,
+            msg_id: 1,
+            increments: HashMap::new(),
+            decrements: HashMap::new(),
+        })
+    }
+
+    fn process_message(
+        &mut self,
+        event: Event<Payload, InjectedPayload>,
+        output: &mut Output,
+    ) -> Result<()> {
+        match event {
+            Event::Message(msg) => match msg.body.payload.clone() {
+                Payload::Add { delta } => {
+                    self.add(delta);
+                    self.reply(msg, Payload::AddOk {}).send(output)?;
+                }
+                Payload::Read {} => {
+                    let value = self.value();
+                    self.reply(msg, Payload::ReadOk { value }).send(output)?;
+                }
+                Payload::Gossip {
+                    increments,
+                    decrements,
+                } => {
+                    Self::merge(&mut self.increments, increments);
+                    Self::merge(&mut self.decrements, decrements);
+                }
+                Payload::AddOk {} | Payload::ReadOk { .. } => {}
+            },
+            Event::Injected(InjectedPayload::GossipTrigger) => {
+                // State-based replication: ship the whole state every tick, so a peer that
+                // missed earlier rounds (e.g. behind a partition) catches up in one message.
+                for node in self.neighbours.clone() {
+                    let msg = Message::new(
+                        self.id.clone(),
+                        node,
+                        Body::new(
+                            Some(self.next_msg_id()),
+                            Payload::Gossip {
+                                increments: self.increments.clone(),
+                                decrements: self.decrements.clone(),
+                            },
+                        ),
+                    );
+                    msg.send(output)?;
+                }
+            }
+            Event::EOF => {}
+        }
+
+        Ok(())
+    }
+}
+
+pub fn main() -> Result<()> {
+    main_loop::<CounterNode, Payload, InjectedPayload>()
+}
+
+#[cfg(test)]
+mod tests {
+    use maelstrom_node::{check_counter, SimConfig, Simulation};
+
+    use super::*;
+
+    type Sim = Simulation<CounterNode, Payload, InjectedPayload>;
+
+    fn send(sim: &mut Sim, step: i64, payload: Payload) {
+        let (client, node) = (format!("c{}", step % 3 + 1), format!("n{}", step % 3 + 1));
+        sim.send(&client, &node, payload).unwrap();
+        sim.run_for(Duration::from_millis(50)).unwrap();
+    }
+
+    #[test]
+    fn replicas_converge_on_mixed_deltas_once_a_partition_heals() {
+        let mut sim = Sim::new(3, SimConfig::default()).unwrap();
+        sim.nemesis_mut().isolate("n1");
+        let mut total = 0;
+        for step in 0..30 {
+            let delta = if step % 2 == 0 { step } else { -2 * step };
+            send(&mut sim, step, Payload::Read {});
+            send(&mut sim, step, Payload::Add { delta });
+            total += delta;
+        }
+        assert_ne!(sim.node("n1").unwrap().value(), total);
+
+        sim.nemesis_mut().heal();
+        sim.run_for(Duration::from_secs(1)).unwrap();
+        for step in 0..3 {
+            send(&mut sim, step, Payload::Read {});
+        }
+        sim.run_for(Duration::from_millis(100)).unwrap();
+
+        for id in ["n1", "n2", "n3"] {
+            let node = sim.node(id).unwrap();
+            assert_eq!(node.value(), total, "{id}");
+            assert!(node.decrements.values().sum::<u64>() > 0);
+        }
+        check_counter(sim.history()).unwrap();
+    }
+}
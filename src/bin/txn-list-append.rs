@@ -0,0 +1,328 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::Result;
+use maelstrom_node::{
+    main_loop, payload, CallOptions, Event, Identity, Injector, Kv, KvError, KvService, Message,
+    Node, Output, Rpc,
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+const ROOT_KEY: &str = "root";
+
+/// How long to wait before asking `lww-kv` again for a thunk it hasn't seen yet.
+const REFETCH_DELAY: Duration = Duration::from_millis(20);
+
+/// A micro-operation: `["r", 1, null]` reads a list, `["append", 1, 6]` appends to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Read { key: u64, value: Option<Vec<u64>> },
+    Append { key: u64, value: u64 },
+}
+
+impl Serialize for Op {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Op::Read { key, value } => ("r", key, value).serialize(serializer),
+            Op::Append { key, value } => ("append", key, value).serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Op {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (kind, key, value) = <(String, u64, Value)>::deserialize(deserializer)?;
+        match kind.as_str() {
+            "r" => Ok(Op::Read {
+                key,
+                value: Deserialize::deserialize(value).map_err(de::Error::custom)?,
+            }),
+            "append" => Ok(Op::Append {
+                key,
+                value: Deserialize::deserialize(value).map_err(de::Error::custom)?,
+            }),
+            other => Err(de::Error::unknown_variant(other, &["r", "append"])),
+        }
+    }
+}
+
+#[payload]
+#[derive(Debug, Clone)]
+pub enum Payload {
+    Txn { txn: Vec<Op> },
+    TxnOk { txn: Vec<Op> },
+}
+
+/// Maps each list key to the id of the immutable thunk in `lww-kv` holding its current value.
+type Root = HashMap<String, String>;
+
+enum InjectedPayload {
+    /// Picks a transaction back up once `REFETCH_DELAY` has passed.
+    Refetch(Attempt),
+}
+
+/// A transaction in flight between its asynchronous steps.
+struct Attempt {
+    msg: Message<Payload>,
+    txn: Vec<Op>,
+    root: Root,
+    to_fetch: Vec<String>,
+}
+
+/// Strict-serializable list-append in the style of Datomic: every list value is written once
+/// to `lww-kv` under a fresh thunk id, and a transaction commits by swapping the single root
+/// pointer in `lin-kv` from the map it read to one naming its new thunks. Losing that race
+/// means someone else committed first, so the transaction starts over against the new root.
+#[derive(Identity)]
+struct ListAppendNode {
+    id: String,
+    msg_id: usize,
+    rpc: Rpc<Self>,
+    tx: Injector<InjectedPayload>,
+    root_store: Kv,
+    thunk_store: Kv,
+    next_thunk: usize,
+    thunks: HashMap<String, Vec<u64>>,
+}
+
+impl ListAppendNode {
+    fn begin(&mut self, msg: Message<Payload>, txn: Vec<Op>, output: &mut Output) -> Result<()> {
+        self.root_store.clone().read(
+            self,
+            ROOT_KEY,
+            output,
+            move |node: &mut Self, root: Result<Root, KvError>, output| {
+                let root = match root {
+                    Ok(root) => root,
+                    Err(KvError::KeyDoesNotExist) => Root::new(),
+                    Err(err) => return node.reply_error(msg, err.into()).send(output),
+                };
+                let to_fetch = txn
+                    .iter()
+                    .filter_map(|op| {
+                        let (Op::Read { key, .. } | Op::Append { key, .. }) = op;
+                        root.get(&key.to_string()).cloned()
+                    })
+                    .collect();
+                let attempt = Attempt {
+                    msg,
+                    txn,
+                    root,
+                    to_fetch,
+                };
+                node.fetch(attempt, output)
+            },
+        )
+    }
+
+    /// Loads every thunk the transaction touches that isn't cached yet. Thunks never change,
+    /// so one that `lww-kv` doesn't have yet is asked for again after `REFETCH_DELAY`.
+    fn fetch(&mut self, mut attempt: Attempt, output: &mut Output) -> Result<()> {
+        attempt.to_fetch.retain(|thunk| !self.thunks.contains_key(thunk));
+        let Some(thunk) = attempt.to_fetch.last().cloned() else {
+            return self.commit(attempt, output);
+        };
+        self.thunk_store.clone().read(
+            self,
+            thunk.clone(),
+            output,
+            move |node: &mut Self, list: Result<Vec<u64>, KvError>, output| match list {
+                Ok(list) => {
+                    node.thunks.insert(thunk, list);
+                    node.fetch(attempt, output)
+                }
+                Err(KvError::KeyDoesNotExist) => {
+                    node.tx.schedule_once(REFETCH_DELAY, InjectedPayload::Refetch(attempt));
+                    Ok(())
+                }
+                Err(err) => node.reply_error(attempt.msg, err.into()).send(output),
+            },
+        )
+    }
+
+    fn commit(&mut self, attempt: Attempt, output: &mut Output) -> Result<()> {
+        let mut lists: HashMap<u64, Vec<u64>> = HashMap::new();
+        let mut changed = Vec::new();
+        let mut completed = Vec::with_capacity(attempt.txn.len());
+        for op in &attempt.txn {
+            let (Op::Read { key, .. } | Op::Append { key, .. }) = *op;
+            let list = lists.entry(key).or_insert_with(|| {
+                attempt
+                    .root
+                    .get(&key.to_string())
+                    .and_then(|thunk| self.thunks.get(thunk))
+                    .cloned()
+                    .unwrap_or_default()
+            });
+            match op {
+                Op::Read { .. } => completed.push(Op::Read {
+                    key,
+                    value: Some(list.clone()),
+                }),
+                Op::Append { value, .. } => {
+                    list.push(*value);
+                    if !changed.contains(&key) {
+                        changed.push(key);
+                    }
+                    completed.push(op.clone());
+                }
+            }
+        }
+
+        // Reading the root from lin-kv already makes a read-only transaction linearizable.
+        if changed.is_empty() {
+            return self.reply(attempt.msg, Payload::TxnOk { txn: completed }).send(output);
+        }
+
+        let mut root = attempt.root.clone();
+        let mut writes = Vec::new();
+        for key in changed {
+            let thunk = format!("{}-{}", self.id, self.next_thunk);
+            self.next_thunk += 1;
+            root.insert(key.to_string(), thunk.clone());
+            writes.push((thunk, lists.remove(&key).unwrap_or_default()));
+        }
+        self.store(attempt, writes, root, completed, output)
+    }
+
+    /// Writes the new thunks one at a time, then publishes them by swapping the root.
+    fn store(
+        &mut self,
+        attempt: Attempt,
+        mut writes: Vec<(String, Vec<u64>)>,
+        root: Root,
+        completed: Vec<Op>,
+        output: &mut Output,
+    ) -> Result<()> {
+        if let Some((thunk, list)) = writes.pop() {
+            self.thunks.insert(thunk.clone(), list.clone());
+            return self.thunk_store.clone().write(
+                self,
+                thunk,
+                list,
+                output,
+                move |node: &mut Self, result, output| match result {
+                    Ok(()) => node.store(attempt, writes, root, completed, output),
+                    Err(err) => node.reply_error(attempt.msg, err.into()).send(output),
+                },
+            );
+        }
+
+        let previous = attempt.root.clone();
+        self.root_store.clone().cas_or_create(
+            self,
+            ROOT_KEY,
+            previous,
+            root,
+            output,
+            move |node: &mut Self, result, output| match result {
+                Ok(()) => node
+                    .reply(attempt.msg, Payload::TxnOk { txn: completed })
+                    .send(output),
+                Err(KvError::PreconditionFailed) => node.begin(attempt.msg, attempt.txn, output),
+                Err(err) => node.reply_error(attempt.msg, err.into()).send(output),
+            },
+        )
+    }
+}
+
+impl Node<Payload, InjectedPayload> for ListAppendNode {
+    fn from_init(init: maelstrom_node::Init, tx: Injector<InjectedPayload>) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        let options = CallOptions::default().with_timeout(Duration::from_secs(1));
+        Ok(Self {
+            id: init.node_id,
+            msg_id: 1,
+            rpc: Rpc::new(),
+            tx,
+            root_store: Kv::new(KvService::LinKv).with_options(options.clone()),
+            thunk_store: Kv::new(KvService::LwwKv).with_options(options),
+            next_thunk: 0,
+            thunks: HashMap::new(),
+        })
+    }
+
+    fn rpc(&mut self) -> Option<&mut Rpc<Self>> {
+        Some(&mut self.rpc)
+    }
+
+    fn process_message(
+        &mut self,
+        event: Event<Payload, InjectedPayload>,
+        output: &mut Output,
+    ) -> Result<()> {
+        let msg = match event {
+            Event::Message(msg) => msg,
+            Event::Injected(InjectedPayload::Refetch(attempt)) => {
+                return self.fetch(attempt, output);
+            }
+            Event::EOF => return Ok(()),
+        };
+
+        match msg.body.payload.clone() {
+            Payload::Txn { txn } => self.begin(msg, txn, output)?,
+            Payload::TxnOk { .. } => {}
+        }
+
+        Ok(())
+    }
+}
+
+pub fn main() -> Result<()> {
+    main_loop::<ListAppendNode, Payload, InjectedPayload>()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn ops_round_trip_as_heterogenous_arrays() {
+        let wire = json!([
+            ["r", 1, null],
+            ["append", 1, 6],
+            ["r", 1, [3, 6]],
+            ["r", 2, []]
+        ]);
+        let txn: Vec<Op> = serde_json::from_value(wire.clone()).unwrap();
+        assert_eq!(
+            txn,
+            [
+                Op::Read {
+                    key: 1,
+                    value: None
+                },
+                Op::Append { key: 1, value: 6 },
+                Op::Read {
+                    key: 1,
+                    value: Some(vec![3, 6])
+                },
+                Op::Read {
+                    key: 2,
+                    value: Some(vec![])
+                },
+            ]
+        );
+        assert_eq!(serde_json::to_value(&txn).unwrap(), wire);
+
+        let reply = serde_json::to_value(Payload::TxnOk { txn }).unwrap();
+        assert_eq!(reply, json!({ "type": "txn_ok", "txn": wire }));
+    }
+
+    #[test]
+    fn malformed_ops_are_rejected() {
+        for op in [
+            json!(["append", 1, null]),
+            json!(["append", 1, [6]]),
+            json!(["r", 1, 6]),
+            json!(["w", 1, 6]),
+            json!(["r", 1]),
+        ] {
+            assert!(serde_json::from_value::<Op>(op.clone()).is_err(), "{op}");
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{bail, Result};
+use maelstrom_node::{
+    main_loop, payload, CallOptions, Event, Identity, Injector, Message, Node, Output, RetryPolicy,
+    Rpc,
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// One micro-operation of a transaction. On the wire these are heterogenous arrays such as
+/// `["r", 1, null]` or `["w", 1, 6]`, so they go through a `(String, u64, Option<u64>)` tuple
+/// instead of a derived representation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Read { key: u64, value: Option<u64> },
+    Write { key: u64, value: u64 },
+}
+
+impl Serialize for Op {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            Op::Read { key, value } => ("r", key, value).serialize(serializer),
+            Op::Write { key, value } => ("w", key, Some(value)).serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Op {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (kind, key, value) = <(String, u64, Option<u64>)>::deserialize(deserializer)?;
+        match (kind.as_str(), value) {
+            ("r", value) => Ok(Op::Read { key, value }),
+            ("w", Some(value)) => Ok(Op::Write { key, value }),
+            ("w", None) => Err(de::Error::custom("write micro-op needs a value")),
+            (other, _) => Err(de::Error::unknown_variant(other, &["r", "w"])),
+        }
+    }
+}
+
+#[payload]
+#[derive(Debug, Clone)]
+pub enum Payload {
+    Txn { txn: Vec<Op> },
+    TxnOk { txn: Vec<Op> },
+    Replicate { writes: Vec<(u64, u64)> },
+    ReplicateOk {},
+}
+
+/// Isolation level; picked with the `TXN_MODE` environment variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Isolation {
+    /// Every write is shipped to peers as it happened, intermediate values included.
+    ReadUncommitted,
+    /// Only each key's final value is shipped, once the whole transaction has applied.
+    ReadCommitted,
+}
+
+#[derive(Identity)]
+struct TxnNode {
+    id: String,
+    peers: Vec<String>,
+    msg_id: usize,
+    rpc: Rpc<Self>,
+    isolation: Isolation,
+    registers: HashMap<u64, u64>,
+}
+
+impl TxnNode {
+    fn execute(&mut self, txn: Vec<Op>) -> (Vec<Op>, Vec<(u64, u64)>) {
+        let mut writes = Vec::new();
+        let completed = txn
+            .into_iter()
+            .map(|op| match op {
+                Op::Read { key, .. } => Op::Read {
+                    key,
+                    value: self.registers.get(&key).copied(),
+                },
+                Op::Write { key, value } => {
+                    self.registers.insert(key, value);
+                    writes.push((key, value));
+                    op
+                }
+            })
+            .collect();
+
+        if self.isolation == Isolation::ReadCommitted {
+            // Later writes to a key overwrite earlier ones, leaving only committed values.
+            let finals: HashMap<u64, u64> = writes.into_iter().collect();
+            writes = finals.into_iter().collect();
+        }
+        (completed, writes)
+    }
+
+    fn replicate(&mut self, writes: Vec<(u64, u64)>, output: &mut Output) -> Result<()> {
+        if writes.is_empty() {
+            return Ok(());
+        }
+        let retry = RetryPolicy::exponential(Duration::from_millis(200), Duration::from_secs(2), 0.5);
+        for node in self.peers.clone() {
+            let msg = self.request(node, Payload::Replicate { writes: writes.clone() });
+            self.rpc.call_with(
+                &msg,
+                &mut *output,
+                CallOptions::default().with_retry(retry.clone()),
+                |_: &mut Self, _: Result<Message<Payload>, _>, _| Ok(()),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Node<Payload, ()> for TxnNode {
+    fn from_init(init: maelstrom_node::Init, _tx: Injector<()>) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        let isolation = match std::env::var("TXN_MODE").as_deref() {
+            Ok("read-uncommitted") => Isolation::ReadUncommitted,
+            Ok("read-committed") | Err(_) => Isolation::ReadCommitted,
+            Ok(other) => {
+                bail!("unknown TXN_MODE {other:?}, expected read-uncommitted or read-committed")
+            }
+        };
+
+        let mut peers = init.node_ids;
+        peers.retain_mut(|id| (*id).ne(&init.node_id));
+        Ok(Self {
+            id: init.node_id,
+            peers,
+            msg_id: 1,
+            rpc: Rpc::new(),
+            isolation,
+            registers: HashMap::new(),
+        })
+    }
+
+    fn rpc(&mut self) -> Option<&mut Rpc<Self>> {
+        Some(&mut self.rpc)
+    }
+
+    fn process_message(&mut self, event: Event<Payload, ()>, output: &mut Output) -> Result<()> {
+        let Event::Message(msg) = event else {
+            return Ok(());
+        };
+
+        match msg.body.payload.clone() {
+            Payload::Txn { txn } => {
+                let (txn, writes) = self.execute(txn);
+                self.replicate(writes, &mut *output)?;
+                self.reply(msg, Payload::TxnOk { txn }).send(output)?;
+            }
+            Payload::Replicate { writes } => {
+                self.registers.extend(writes);
+                self.reply(msg, Payload::ReplicateOk {}).send(output)?;
+            }
+            Payload::TxnOk { .. } | Payload::ReplicateOk {} => {}
+        }
+
+        Ok(())
+    }
+}
+
+pub fn main() -> Result<()> {
+    main_loop::<TxnNode, Payload, ()>()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn ops_round_trip_as_heterogenous_arrays() {
+        let wire = json!([["r", 1, null], ["r", 2, 3], ["w", 1, 6]]);
+        let txn: Vec<Op> = serde_json::from_value(wire.clone()).unwrap();
+        assert_eq!(
+            txn,
+            [
+                Op::Read {
+                    key: 1,
+                    value: None
+                },
+                Op::Read {
+                    key: 2,
+                    value: Some(3)
+                },
+                Op::Write { key: 1, value: 6 },
+            ]
+        );
+        assert_eq!(serde_json::to_value(&txn).unwrap(), wire);
+
+        let reply = serde_json::to_value(Payload::TxnOk { txn }).unwrap();
+        assert_eq!(reply, json!({ "type": "txn_ok", "txn": wire }));
+    }
+
+    #[test]
+    fn malformed_ops_are_rejected() {
+        for op in [
+            json!(["w", 1, null]),
+            json!(["cas", 1, 2]),
+            json!(["r", 1]),
+            json!(["r", -1, 2]),
+        ] {
+            assert!(serde_json::from_value::<Op>(op.clone()).is_err(), "{op}");
+        }
+    }
+}
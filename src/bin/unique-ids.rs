@@ -1,12 +1,9 @@
-use std::{io::StdoutLock, sync::mpsc::Sender};
 
 use anyhow::Result;
-use maelstrom_node::{main_loop, Node, Event};
-use serde::{Deserialize, Serialize};
+use maelstrom_node::{main_loop, payload, Event, Identity, Injector, Node, Output};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
+#[payload]
+#[derive(Debug, Clone)]
 pub enum Payload {
     Generate {},
     GenerateOk {
@@ -19,14 +16,14 @@ fn generate_unique_id() -> String {
     ulid::Ulid::new().to_string()
 }
 
-#[derive(Default)]
+#[derive(Default, Identity)]
 struct UniqueIdNode {
     id: String,
     msg_id: usize,
 }
 
 impl Node<Payload, ()> for UniqueIdNode {
-    fn from_init(init: maelstrom_node::Init, _tx: Sender<Event<Payload, ()>>) -> Result<Self>
+    fn from_init(init: maelstrom_node::Init, _tx: Injector<()>) -> Result<Self>
     where
         Self: Sized,
     {
@@ -36,19 +33,9 @@ impl Node<Payload, ()> for UniqueIdNode {
         })
     }
 
-    fn next_msg_id(&mut self) -> usize {
-        let out = self.msg_id;
-        self.msg_id += 1;
-        out
-    }
-
-    fn node_id(&self) -> String {
-        self.id.clone()
-    }
-
-    fn process_message(&mut self, event: Event<Payload, ()>, output: &mut StdoutLock) -> Result<()> {
+    fn process_message(&mut self, event: Event<Payload, ()>, output: &mut Output) -> Result<()> {
         let Event::Message(msg) = event else {
-            panic!("Injected event where there's not supposed to be");
+            return Ok(());
         };
 
         match msg.body.payload {
@@ -0,0 +1,578 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, VecDeque},
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    rc::Rc,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Wake, Waker},
+    time::Duration,
+};
+
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{
+    main_loop, Body, CallOptions, Error, Event, Identity, Init, Injector, Message, Node, Output,
+    Rpc, RpcError, TimerId,
+};
+
+type Task = Pin<Box<dyn Future<Output = Result<()>>>>;
+
+/// A node whose handlers are futures. Every inbound message gets its own task, so a handler
+/// can `await` replies and timers through its `AsyncContext` while other messages are served.
+///
+/// Tasks run one at a time on the main loop's thread and only switch at an `await`, so state
+/// behind a `RefCell` or `Cell` is safe to use as long as no borrow is held across one.
+pub trait AsyncNode<Payload>: Sized + 'static {
+    fn from_init(init: Init, ctx: &AsyncContext) -> Result<Self>;
+    fn handle(
+        self: Rc<Self>,
+        msg: Message<Payload>,
+        ctx: AsyncContext,
+    ) -> impl Future<Output = Result<()>>;
+}
+
+/// Runs an `AsyncNode` on top of `main_loop`, which keeps driving its rpc table and timers.
+pub fn async_main_loop<N, Payload>() -> Result<()>
+where
+    N: AsyncNode<Payload>,
+    Payload: DeserializeOwned + 'static,
+{
+    main_loop::<Driver<N, Payload>, Payload, Wakeup>()
+}
+
+/// Where a reply or timer leaves its result for the future waiting on it.
+struct Slot<T> {
+    value: Option<T>,
+    waker: Option<Waker>,
+}
+
+type SharedSlot<T> = Arc<Mutex<Slot<T>>>;
+
+fn new_slot<T>() -> SharedSlot<T> {
+    Arc::new(Mutex::new(Slot {
+        value: None,
+        waker: None,
+    }))
+}
+
+fn fill<T>(slot: &SharedSlot<T>, value: T) {
+    let mut slot = slot.lock().expect("slot lock poisoned");
+    slot.value = Some(value);
+    if let Some(waker) = slot.waker.take() {
+        waker.wake();
+    }
+}
+
+fn poll_slot<T>(slot: &SharedSlot<T>, cx: &mut Context<'_>) -> Poll<T> {
+    let mut slot = slot.lock().expect("slot lock poisoned");
+    match slot.value.take() {
+        Some(value) => Poll::Ready(value),
+        None => {
+            slot.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// Timer payload that completes a `Sleep`.
+struct Wakeup(SharedSlot<()>);
+
+/// Work a task asked for, carried out by the driver once the task yields.
+enum Command {
+    Send(Message<Value>),
+    Call {
+        msg: Message<Value>,
+        options: CallOptions,
+        slot: SharedSlot<Result<Message<Value>, RpcError>>,
+    },
+}
+
+struct Shared {
+    node_id: String,
+    node_ids: Vec<String>,
+    msg_id: Cell<usize>,
+    injector: Injector<Wakeup>,
+    commands: RefCell<Vec<Command>>,
+    spawned: RefCell<Vec<Task>>,
+}
+
+/// A task's handle on its node: sending, calling peers, sleeping and spawning more tasks.
+#[derive(Clone)]
+pub struct AsyncContext {
+    shared: Rc<Shared>,
+}
+
+impl AsyncContext {
+    pub fn node_id(&self) -> &str {
+        &self.shared.node_id
+    }
+
+    pub fn node_ids(&self) -> &[String] {
+        &self.shared.node_ids
+    }
+
+    pub fn next_msg_id(&self) -> usize {
+        let id = self.shared.msg_id.get();
+        self.shared.msg_id.set(id + 1);
+        id
+    }
+
+    fn message(&self, dest: String, body: Body<impl Serialize>) -> Result<Message<Value>> {
+        Ok(Message {
+            src: self.shared.node_id.clone(),
+            dest,
+            body: Body {
+                id: body.id,
+                reply_to: body.reply_to,
+                payload: serde_json::to_value(body.payload)?,
+            },
+        })
+    }
+
+    /// Sends a message without expecting an answer.
+    pub fn send(&self, dest: String, payload: impl Serialize) -> Result<()> {
+        let msg = self.message(dest, Body::new(Some(self.next_msg_id()), payload))?;
+        self.shared.commands.borrow_mut().push(Command::Send(msg));
+        Ok(())
+    }
+
+    pub fn reply<Req>(&self, msg: &Message<Req>, payload: impl Serialize) -> Result<()> {
+        let body = Body {
+            id: Some(self.next_msg_id()),
+            reply_to: msg.body.id,
+            payload,
+        };
+        let reply = self.message(msg.src.clone(), body)?;
+        self.shared.commands.borrow_mut().push(Command::Send(reply));
+        Ok(())
+    }
+
+    pub fn reply_error<Req>(&self, msg: &Message<Req>, error: Error) -> Result<()> {
+        self.reply(msg, error)
+    }
+
+    /// Sends a request to `dest`; the returned future resolves with its reply. Never times out.
+    pub fn call<Resp>(&self, dest: String, payload: impl Serialize) -> Result<Call<Resp>> {
+        self.call_with(dest, payload, CallOptions::default())
+    }
+
+    /// Like `call`, with the timeout and retries of `options`. The request goes out as soon as
+    /// the calling task yields, whether or not the future is awaited.
+    pub fn call_with<Resp>(
+        &self,
+        dest: String,
+        payload: impl Serialize,
+        options: CallOptions,
+    ) -> Result<Call<Resp>> {
+        let msg = self.message(dest, Body::new(Some(self.next_msg_id()), payload))?;
+        let slot = new_slot();
+        self.shared.commands.borrow_mut().push(Command::Call {
+            msg,
+            options,
+            slot: slot.clone(),
+        });
+        Ok(Call {
+            slot,
+            _resp: PhantomData,
+        })
+    }
+
+    /// A future that resolves `after` from now, timed by the main loop's timers.
+    pub fn sleep(&self, after: Duration) -> Sleep {
+        let slot = new_slot();
+        let timer = self
+            .shared
+            .injector
+            .schedule_once(after, Wakeup(slot.clone()));
+        Sleep {
+            slot,
+            timer,
+            injector: self.shared.injector.clone(),
+        }
+    }
+
+    /// Runs `future` as a task of its own, alongside the one that spawned it.
+    pub fn spawn(&self, future: impl Future<Output = Result<()>> + 'static) {
+        self.shared.spawned.borrow_mut().push(Box::pin(future));
+    }
+}
+
+pub struct Call<Resp> {
+    slot: SharedSlot<Result<Message<Value>, RpcError>>,
+    _resp: PhantomData<fn() -> Resp>,
+}
+
+impl<Resp: DeserializeOwned> Future for Call<Resp> {
+    type Output = Result<Message<Resp>, RpcError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        poll_slot(&self.slot, cx).map(|reply| {
+            reply.and_then(|msg| {
+                msg.decode()
+                    .map_err(|err| RpcError::Malformed(err.to_string()))
+            })
+        })
+    }
+}
+
+pub struct Sleep {
+    slot: SharedSlot<()>,
+    timer: TimerId,
+    injector: Injector<Wakeup>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        poll_slot(&self.slot, cx)
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.injector.cancel(self.timer);
+    }
+}
+
+struct TaskWaker {
+    id: usize,
+    ready: Arc<Mutex<VecDeque<usize>>>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.ready
+            .lock()
+            .expect("ready lock poisoned")
+            .push_back(self.id);
+    }
+}
+
+/// Adapts an `AsyncNode` to `Node`: messages become tasks, and whenever a message, reply or
+/// timer wakes one up the woken tasks are polled until they all wait again.
+struct Driver<N, Payload> {
+    node: Rc<N>,
+    ctx: AsyncContext,
+    rpc: Rpc<Self>,
+    tasks: HashMap<usize, Task>,
+    next_task: usize,
+    ready: Arc<Mutex<VecDeque<usize>>>,
+    _payload: PhantomData<fn(Payload)>,
+}
+
+impl<N, Payload> Driver<N, Payload>
+where
+    N: AsyncNode<Payload>,
+    Payload: 'static,
+{
+    fn run(&mut self, output: &mut Output) -> Result<()> {
+        loop {
+            for task in self.ctx.shared.spawned.take() {
+                let id = self.next_task;
+                self.next_task += 1;
+                self.tasks.insert(id, task);
+                self.ready
+                    .lock()
+                    .expect("ready lock poisoned")
+                    .push_back(id);
+            }
+            let Some(id) = self.ready.lock().expect("ready lock poisoned").pop_front() else {
+                return Ok(());
+            };
+            // Wakers may fire more than once, or after their task finished.
+            let Some(task) = self.tasks.get_mut(&id) else {
+                continue;
+            };
+            let waker = Waker::from(Arc::new(TaskWaker {
+                id,
+                ready: self.ready.clone(),
+            }));
+            let poll = task.as_mut().poll(&mut Context::from_waker(&waker));
+            self.flush(output)?;
+            if let Poll::Ready(result) = poll {
+                self.tasks.remove(&id);
+                result?;
+            }
+        }
+    }
+
+    fn flush(&mut self, output: &mut Output) -> Result<()> {
+        for command in self.ctx.shared.commands.take() {
+            match command {
+                Command::Send(msg) => msg.send(&mut *output)?,
+                Command::Call { msg, options, slot } => self.rpc.call_with(
+                    &msg,
+                    &mut *output,
+                    options,
+                    move |driver: &mut Self, reply, output| {
+                        fill(&slot, reply);
+                        driver.run(output)
+                    },
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<N, Payload> Identity for Driver<N, Payload> {
+    fn next_msg_id(&mut self) -> usize {
+        self.ctx.next_msg_id()
+    }
+
+    fn node_id(&self) -> String {
+        self.ctx.shared.node_id.clone()
+    }
+}
+
+impl<N, Payload> Node<Payload, Wakeup> for Driver<N, Payload>
+where
+    N: AsyncNode<Payload>,
+    Payload: 'static,
+{
+    fn from_init(init: Init, tx: Injector<Wakeup>) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        let ctx = AsyncContext {
+            shared: Rc::new(Shared {
+                node_id: init.node_id.clone(),
+                node_ids: init.node_ids.clone(),
+                msg_id: Cell::new(1),
+                injector: tx,
+                commands: RefCell::new(Vec::new()),
+                spawned: RefCell::new(Vec::new()),
+            }),
+        };
+        let node = Rc::new(N::from_init(init, &ctx)?);
+        // Gets any tasks spawned by `from_init` going without waiting for the first message.
+        ctx.shared.injector.inject(Wakeup(new_slot()))?;
+        Ok(Self {
+            node,
+            ctx,
+            rpc: Rpc::new(),
+            tasks: HashMap::new(),
+            next_task: 0,
+            ready: Arc::new(Mutex::new(VecDeque::new())),
+            _payload: PhantomData,
+        })
+    }
+
+    fn rpc(&mut self) -> Option<&mut Rpc<Self>> {
+        Some(&mut self.rpc)
+    }
+
+    fn process_message(
+        &mut self,
+        event: Event<Payload, Wakeup>,
+        output: &mut Output,
+    ) -> Result<()> {
+        match event {
+            Event::Message(msg) => {
+                let task = self.node.clone().handle(msg, self.ctx.clone());
+                self.ctx.spawn(task);
+            }
+            Event::Injected(Wakeup(slot)) => fill(&slot, ()),
+            Event::EOF => {}
+        }
+        self.run(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{payload, ErrorCode, SimConfig, Simulation};
+
+    #[payload]
+    #[derive(Debug, Clone)]
+    enum Payload {
+        Hello {},
+        Nap { ms: u64 },
+        NapOk {},
+        Fan { count: u64 },
+        FanOk {},
+        Fanned { n: u64 },
+        Abandon {},
+        AbandonOk {},
+        Ask { fail: bool },
+        AskOk { answer: String },
+        Misread {},
+        Echo { fail: bool },
+        EchoOk { from: String },
+    }
+
+    /// What `Misread` wrongly expects an `Echo` to be answered with.
+    #[derive(Deserialize)]
+    struct Unrelated {
+        #[allow(dead_code)]
+        value: u64,
+    }
+
+    struct Tester;
+
+    impl AsyncNode<Payload> for Tester {
+        fn from_init(_init: Init, ctx: &AsyncContext) -> Result<Self> {
+            let background = ctx.clone();
+            ctx.spawn(async move {
+                background.sleep(Duration::from_millis(5)).await;
+                background.send("c0".to_string(), Payload::Hello {})
+            });
+            Ok(Self)
+        }
+
+        async fn handle(self: Rc<Self>, msg: Message<Payload>, ctx: AsyncContext) -> Result<()> {
+            match msg.body.payload {
+                Payload::Nap { ms } => {
+                    ctx.sleep(Duration::from_millis(ms)).await;
+                    ctx.reply(&msg, Payload::NapOk {})
+                }
+                Payload::Fan { count } => {
+                    for n in 0..count {
+                        let task = ctx.clone();
+                        ctx.spawn(async move {
+                            task.sleep(Duration::from_millis(10 * (count - n))).await;
+                            task.send("c1".to_string(), Payload::Fanned { n })
+                        });
+                    }
+                    ctx.reply(&msg, Payload::FanOk {})
+                }
+                Payload::Abandon {} => {
+                    drop(ctx.sleep(Duration::from_secs(1)));
+                    ctx.reply(&msg, Payload::AbandonOk {})
+                }
+                Payload::Ask { fail } => {
+                    let call = ctx.call("n2".to_string(), Payload::Echo { fail })?;
+                    match call.await.map(|reply: Message<Payload>| reply.body.payload) {
+                        Ok(Payload::EchoOk { from }) => {
+                            ctx.reply(&msg, Payload::AskOk { answer: from })
+                        }
+                        Ok(other) => panic!("echo answered with {other:?}"),
+                        Err(err) => ctx.reply_error(&msg, err.into()),
+                    }
+                }
+                Payload::Misread {} => {
+                    let call = ctx.call("n2".to_string(), Payload::Echo { fail: false })?;
+                    let reply: Result<Message<Unrelated>, _> = call.await;
+                    let Err(err) = reply else {
+                        panic!("an echo_ok decoded as something else");
+                    };
+                    ctx.reply_error(&msg, err.into())
+                }
+                Payload::Echo { fail: true } => {
+                    let error = Error::new(ErrorCode::TemporarilyUnavailable, "asked to fail");
+                    ctx.reply_error(&msg, error)
+                }
+                Payload::Echo { fail: false } => {
+                    let from = ctx.node_id().to_string();
+                    ctx.reply(&msg, Payload::EchoOk { from })
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    type Sim = Simulation<Driver<Tester, Payload>, Payload, Wakeup>;
+
+    fn sim() -> Sim {
+        let config = SimConfig {
+            latency: crate::Latency::Fixed(Duration::from_millis(1)),
+            ..SimConfig::default()
+        };
+        Simulation::new(2, config).unwrap()
+    }
+
+    /// The payloads clients were sent, in order.
+    fn received(sim: &mut Sim) -> Vec<Value> {
+        sim.take_client_messages()
+            .into_iter()
+            .map(|msg| msg.body.payload)
+            .collect()
+    }
+
+    #[test]
+    fn tasks_spawned_at_init_run_without_a_message() {
+        let mut sim = sim();
+        sim.run_for(Duration::from_millis(4)).unwrap();
+        assert!(received(&mut sim).is_empty());
+        sim.run_for(Duration::from_millis(2)).unwrap();
+        let hellos = sim.take_client_messages();
+        assert_eq!(hellos.len(), 2);
+        assert!(hellos.iter().all(|msg| msg.dest == "c0"));
+    }
+
+    #[test]
+    fn sleeping_tasks_wake_while_others_are_served() {
+        let mut sim = sim();
+        sim.run_for(Duration::from_millis(10)).unwrap();
+        sim.take_client_messages();
+        sim.send("c1", "n1", Payload::Nap { ms: 50 }).unwrap();
+        sim.send("c1", "n1", Payload::Echo { fail: false }).unwrap();
+        sim.run_for(Duration::from_millis(40)).unwrap();
+        assert_eq!(
+            received(&mut sim),
+            [json!({"type": "echo_ok", "from": "n1"})]
+        );
+        sim.run_for(Duration::from_millis(20)).unwrap();
+        assert_eq!(received(&mut sim), [json!({"type": "nap_ok"})]);
+
+        sim.send("c1", "n1", Payload::Fan { count: 3 }).unwrap();
+        sim.run_for(Duration::from_millis(100)).unwrap();
+        let order: Vec<Value> = received(&mut sim)
+            .into_iter()
+            .map(|p| p["n"].clone())
+            .collect();
+        assert_eq!(order, [Value::Null, 2.into(), 1.into(), 0.into()]);
+    }
+
+    #[test]
+    fn dropping_a_sleep_cancels_its_timer() {
+        let mut sim = sim();
+        sim.run_for(Duration::from_millis(10)).unwrap();
+        sim.send("c1", "n1", Payload::Abandon {}).unwrap();
+        sim.run_for(Duration::from_millis(10)).unwrap();
+        let replies = received(&mut sim);
+        assert_eq!(replies.last(), Some(&json!({"type": "abandon_ok"})));
+        // Nothing is left to wake up for: no message in flight and no timer armed.
+        assert!(!sim.step().unwrap());
+    }
+
+    #[test]
+    fn calls_resolve_with_decoded_replies_and_remote_errors() {
+        let mut sim = sim();
+        let code = |reply: &Message<Value>| reply.body.payload["code"].clone();
+
+        let reply: Message<Payload> = sim
+            .call(
+                "c1",
+                "n1",
+                Payload::Ask { fail: false },
+                Duration::from_secs(1),
+            )
+            .unwrap();
+        assert!(matches!(reply.body.payload, Payload::AskOk { answer } if answer == "n2"));
+
+        let reply: Message<Value> = sim
+            .call(
+                "c1",
+                "n1",
+                Payload::Ask { fail: true },
+                Duration::from_secs(1),
+            )
+            .unwrap();
+        assert_eq!(code(&reply), ErrorCode::TemporarilyUnavailable.code());
+
+        let reply: Message<Value> = sim
+            .call("c1", "n1", Payload::Misread {}, Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(code(&reply), ErrorCode::Crash.code());
+    }
+}
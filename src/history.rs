@@ -0,0 +1,537 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
+    time::Duration,
+};
+
+use serde_json::Value;
+
+use crate::{ErrorCode, Message};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    /// The request definitely did not take effect.
+    Fail,
+    /// The request may or may not have taken effect: it timed out, crashed or never returned.
+    Info,
+}
+
+/// One client request and what came of it.
+#[derive(Debug, Clone)]
+pub struct Operation {
+    pub process: String,
+    pub invoked: Duration,
+    /// `None` if no reply ever arrived.
+    pub completed: Option<Duration>,
+    pub outcome: Outcome,
+    pub request: Value,
+    pub response: Option<Value>,
+}
+
+impl Operation {
+    /// The request's `type`, such as `broadcast` or `read`.
+    pub fn f(&self) -> &str {
+        self.request
+            .get("type")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+    }
+}
+
+/// Client requests and replies in the order they were seen, timestamped.
+///
+/// The simulator records every message between clients and nodes here; anything else that
+/// sees client traffic can feed it through `invoke` and `complete`.
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    operations: Vec<Operation>,
+    open: HashMap<(String, usize), usize>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a request sent by a client.
+    pub fn invoke(&mut self, time: Duration, msg: &Message<Value>) {
+        let index = self.operations.len();
+        self.operations.push(Operation {
+            process: msg.src.clone(),
+            invoked: time,
+            completed: None,
+            outcome: Outcome::Info,
+            request: msg.body.payload.clone(),
+            response: None,
+        });
+        if let Some(id) = msg.body.id {
+            self.open.insert((msg.src.clone(), id), index);
+        }
+    }
+
+    /// Records a reply sent to a client. Replies to nothing it saw invoked are ignored.
+    pub fn complete(&mut self, time: Duration, msg: &Message<Value>) {
+        let Some(reply_to) = msg.body.reply_to else {
+            return;
+        };
+        let Some(index) = self.open.remove(&(msg.dest.clone(), reply_to)) else {
+            return;
+        };
+        let outcome = match msg.body.payload.get("type").and_then(Value::as_str) {
+            Some("error") => {
+                let code = msg.body.payload.get("code").and_then(Value::as_u64);
+                match code.map(|code| ErrorCode::from(code as u32)) {
+                    Some(code) if code.is_definite() => Outcome::Fail,
+                    _ => Outcome::Info,
+                }
+            }
+            _ => Outcome::Ok,
+        };
+        let operation = &mut self.operations[index];
+        operation.completed = Some(time);
+        operation.outcome = outcome;
+        operation.response = Some(msg.body.payload.clone());
+    }
+
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    fn of<'a>(&'a self, f: &'a str) -> impl Iterator<Item = &'a Operation> {
+        self.operations.iter().filter(move |op| op.f() == f)
+    }
+
+    /// When the last operation of type `f` finished, counting ones that never did as running
+    /// forever.
+    fn last_completion(&self, f: &str) -> Option<Duration> {
+        self.of(f)
+            .map(|op| op.completed.unwrap_or(Duration::MAX))
+            .max()
+    }
+}
+
+/// What a checker found wrong with a history.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckError {
+    pub anomalies: Vec<String>,
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "history is invalid: {}", self.anomalies.join("; "))
+    }
+}
+
+impl std::error::Error for CheckError {}
+
+fn verdict(anomalies: Vec<String>) -> Result<(), CheckError> {
+    if anomalies.is_empty() {
+        Ok(())
+    } else {
+        Err(CheckError { anomalies })
+    }
+}
+
+/// A payload's `field` as an integer, for fields such as `delta`, `value` and `code`.
+fn int(value: Option<&Value>, field: &str) -> Option<i64> {
+    value?.get(field)?.as_i64()
+}
+
+/// Checks a broadcast history for set completeness: every read that started after the last
+/// `broadcast` finished must contain every acknowledged message, and nothing never broadcast.
+pub fn check_broadcast(history: &History) -> Result<(), CheckError> {
+    let mut attempted = BTreeSet::new();
+    let mut acknowledged = BTreeSet::new();
+    for op in history.of("broadcast") {
+        let Some(message) = int(Some(&op.request), "message") else {
+            continue;
+        };
+        attempted.insert(message);
+        if op.outcome == Outcome::Ok {
+            acknowledged.insert(message);
+        }
+    }
+
+    let settled = history.last_completion("broadcast").unwrap_or_default();
+    let mut anomalies = Vec::new();
+    let mut final_reads = 0;
+    for op in history.of("read") {
+        if op.outcome != Outcome::Ok || op.invoked < settled {
+            continue;
+        }
+        final_reads += 1;
+        let Some(messages) = op.response.as_ref().and_then(|r| r.get("messages")) else {
+            anomalies.push(format!("read by {} returned no messages", op.process));
+            continue;
+        };
+        let seen: BTreeSet<i64> = messages
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_i64)
+            .collect();
+        let lost: Vec<_> = acknowledged.difference(&seen).collect();
+        if !lost.is_empty() {
+            anomalies.push(format!("read by {} is missing {lost:?}", op.process));
+        }
+        let unexpected: Vec<_> = seen.difference(&attempted).collect();
+        if !unexpected.is_empty() {
+            anomalies.push(format!(
+                "read by {} has never-sent {unexpected:?}",
+                op.process
+            ));
+        }
+    }
+    if final_reads == 0 {
+        anomalies.push("no read started after the last broadcast finished".to_string());
+    }
+    verdict(anomalies)
+}
+
+/// Checks a counter history of `add` and `read` operations.
+///
+/// Reads that started after the last `add` finished must land between the sum of acknowledged
+/// deltas and that sum plus whatever indeterminate adds could have contributed. When no delta
+/// is negative, each client's successive reads must also never go down.
+pub fn check_counter(history: &History) -> Result<(), CheckError> {
+    let (mut acknowledged, mut maybe_up, mut maybe_down) = (0, 0, 0);
+    let mut grow_only = true;
+    for op in history.of("add") {
+        let delta = int(Some(&op.request), "delta").unwrap_or_default();
+        grow_only &= delta >= 0;
+        match op.outcome {
+            Outcome::Ok => acknowledged += delta,
+            Outcome::Info if delta >= 0 => maybe_up += delta,
+            Outcome::Info => maybe_down += delta,
+            Outcome::Fail => {}
+        }
+    }
+    let (lower, upper) = (acknowledged + maybe_down, acknowledged + maybe_up);
+
+    let settled = history.last_completion("add").unwrap_or_default();
+    let mut anomalies = Vec::new();
+    let mut last_read: HashMap<&str, i64> = HashMap::new();
+    let mut reads: Vec<_> = history
+        .of("read")
+        .filter(|op| op.outcome == Outcome::Ok)
+        .collect();
+    reads.sort_by_key(|op| op.completed);
+    for op in reads {
+        let Some(value) = int(op.response.as_ref(), "value") else {
+            anomalies.push(format!("read by {} returned no value", op.process));
+            continue;
+        };
+        if op.invoked >= settled && !(lower..=upper).contains(&value) {
+            anomalies.push(format!(
+                "final read by {} saw {value}, expected {lower}..={upper}",
+                op.process
+            ));
+        }
+        if grow_only {
+            let previous = last_read.insert(&op.process, value);
+            if previous.is_some_and(|previous| previous > value) {
+                anomalies.push(format!(
+                    "reads by {} went down from {} to {value}",
+                    op.process,
+                    previous.unwrap_or_default()
+                ));
+            }
+        }
+    }
+    verdict(anomalies)
+}
+
+#[derive(Debug, Clone)]
+enum RegisterOp {
+    Read(Option<Value>),
+    Write(Value),
+    Cas(Value, Value),
+}
+
+struct Call {
+    op: RegisterOp,
+    invoked: Duration,
+    completed: Duration,
+    required: bool,
+}
+
+/// Checks that `read`, `write` and `cas` operations on each key of a key/value store, in
+/// Maelstrom's `lin-kv` format, are linearizable: that some order of them respects real time
+/// and explains every result as a single register would.
+///
+/// Failed operations are left out, except reads of a missing key, which saw no value.
+/// Indeterminate ones may be placed anywhere after they were invoked, or nowhere. The search
+/// is exponential in the worst case, so keep histories small.
+pub fn check_linearizable(history: &History) -> Result<(), CheckError> {
+    let mut keys: BTreeMap<String, Vec<Call>> = BTreeMap::new();
+    for op in history.operations() {
+        let field = |name: &str| op.request.get(name).cloned().unwrap_or_default();
+        let missing =
+            int(op.response.as_ref(), "code") == Some(ErrorCode::KeyDoesNotExist.code() as i64);
+        let op_kind = match (op.f(), op.outcome) {
+            ("read", Outcome::Ok) => {
+                let value = op.response.as_ref().and_then(|r| r.get("value")).cloned();
+                RegisterOp::Read(value)
+            }
+            ("read", Outcome::Fail) if missing => RegisterOp::Read(None),
+            // Other failures, and unanswered reads, can't have changed anything.
+            (_, Outcome::Fail) | ("read", _) => continue,
+            ("write", _) => RegisterOp::Write(field("value")),
+            ("cas", _) => RegisterOp::Cas(field("from"), field("to")),
+            _ => continue,
+        };
+        // The only failures left are reads of a missing key, which are as definite as an ok.
+        let required = op.outcome != Outcome::Info;
+        // An indeterminate call may take effect long after its error reply, if at all, so
+        // it never has to come before the calls that followed it.
+        let completed = match op.completed {
+            Some(completed) if required => completed,
+            _ => Duration::MAX,
+        };
+        keys.entry(field("key").to_string())
+            .or_default()
+            .push(Call {
+                op: op_kind,
+                invoked: op.invoked,
+                completed,
+                required,
+            });
+    }
+
+    let anomalies = keys
+        .into_iter()
+        .filter(|(_, calls)| !Linearizer::new(calls).search(None))
+        .map(|(key, calls)| {
+            format!(
+                "no linear order explains the {} ops on key {key}",
+                calls.len()
+            )
+        })
+        .collect();
+    verdict(anomalies)
+}
+
+/// Depth-first search for a linearization, remembering register states already tried for
+/// each set of placed operations.
+struct Linearizer<'a> {
+    calls: &'a [Call],
+    placed: Vec<bool>,
+    tried: HashSet<(Vec<bool>, Option<String>)>,
+}
+
+impl<'a> Linearizer<'a> {
+    /// Whether the call at `index` was invoked once another unplaced call had already finished,
+    /// so it can't go next. A reply and the next request can share an instant in the simulator.
+    fn follows_unplaced(&self, index: usize) -> bool {
+        let invoked = self.calls[index].invoked;
+        self.calls
+            .iter()
+            .zip(&self.placed)
+            .enumerate()
+            .any(|(other, (call, placed))| other != index && !placed && call.completed <= invoked)
+    }
+
+    fn new(calls: &'a [Call]) -> Self {
+        Self {
+            calls,
+            placed: vec![false; calls.len()],
+            tried: HashSet::new(),
+        }
+    }
+
+    fn search(&mut self, state: Option<Value>) -> bool {
+        let remaining = self.calls.iter().zip(&self.placed);
+        if remaining
+            .filter(|(call, placed)| call.required && !**placed)
+            .count()
+            == 0
+        {
+            return true;
+        }
+        let key = (self.placed.clone(), state.as_ref().map(Value::to_string));
+        if !self.tried.insert(key) {
+            return false;
+        }
+
+        for index in 0..self.calls.len() {
+            let call = &self.calls[index];
+            if self.placed[index] || self.follows_unplaced(index) {
+                continue;
+            }
+            let next = match &call.op {
+                RegisterOp::Read(value) if *value == state => state.clone(),
+                RegisterOp::Read(_) => continue,
+                RegisterOp::Write(value) => Some(value.clone()),
+                RegisterOp::Cas(from, to) if state.as_ref() == Some(from) => Some(to.clone()),
+                RegisterOp::Cas(..) => continue,
+            };
+            self.placed[index] = true;
+            if self.search(next) {
+                return true;
+            }
+            self.placed[index] = false;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::Body;
+
+    /// Builds histories one operation at a time, in milliseconds of virtual time.
+    #[derive(Default)]
+    struct Builder {
+        history: History,
+        next_id: usize,
+    }
+
+    impl Builder {
+        fn op(mut self, process: &str, at: (u64, u64), request: Value, response: Value) -> Self {
+            self.next_id += 1;
+            let request = Message::new(
+                process.to_string(),
+                "n1".to_string(),
+                Body::new(Some(self.next_id), request),
+            );
+            let response = Message::new(
+                "n1".to_string(),
+                process.to_string(),
+                Body {
+                    id: None,
+                    reply_to: Some(self.next_id),
+                    payload: response,
+                },
+            );
+            self.history.invoke(Duration::from_millis(at.0), &request);
+            self.history.complete(Duration::from_millis(at.1), &response);
+            self
+        }
+    }
+
+    fn ok(f: &str) -> Value {
+        json!({ "type": format!("{f}_ok") })
+    }
+
+    fn error(code: ErrorCode) -> Value {
+        json!({ "type": "error", "code": code.code() })
+    }
+
+    fn broadcast(message: i64) -> Value {
+        json!({ "type": "broadcast", "message": message })
+    }
+
+    fn read_messages(messages: &[i64]) -> Value {
+        json!({ "type": "read_ok", "messages": messages })
+    }
+
+    #[test]
+    fn broadcast_checker() {
+        let sent = || {
+            Builder::default()
+                .op("c1", (0, 1), broadcast(1), ok("broadcast"))
+                .op("c2", (0, 2), broadcast(2), ok("broadcast"))
+        };
+        let valid = sent().op("c1", (3, 4), json!({ "type": "read" }), read_messages(&[2, 1]));
+        assert_eq!(check_broadcast(&valid.history), Ok(()));
+
+        let lost = sent().op("c1", (3, 4), json!({ "type": "read" }), read_messages(&[1]));
+        assert!(check_broadcast(&lost.history).is_err());
+
+        let read = json!({ "type": "read" });
+        let invented = sent().op("c1", (3, 4), read, read_messages(&[1, 2, 3]));
+        assert!(check_broadcast(&invented.history).is_err());
+    }
+
+    fn add(delta: i64) -> Value {
+        json!({ "type": "add", "delta": delta })
+    }
+
+    fn read_value(value: i64) -> Value {
+        json!({ "type": "read_ok", "value": value })
+    }
+
+    #[test]
+    fn counter_checker() {
+        let added = || {
+            Builder::default()
+                .op("c1", (0, 1), add(1), ok("add"))
+                .op("c2", (0, 1), add(2), ok("add"))
+                .op("c2", (1, 2), add(4), error(ErrorCode::Crash))
+        };
+        let read = || json!({ "type": "read" });
+        // The indeterminate add of 4 may or may not have landed.
+        let valid = added()
+            .op("c1", (3, 4), read(), read_value(3))
+            .op("c2", (3, 4), read(), read_value(7));
+        assert_eq!(check_counter(&valid.history), Ok(()));
+
+        let too_low = added().op("c1", (3, 4), read(), read_value(2));
+        assert!(check_counter(&too_low.history).is_err());
+
+        let went_down = added()
+            .op("c1", (3, 4), read(), read_value(7))
+            .op("c1", (5, 6), read(), read_value(3));
+        assert!(check_counter(&went_down.history).is_err());
+    }
+
+    fn write(value: i64) -> Value {
+        json!({ "type": "write", "key": 0, "value": value })
+    }
+
+    fn read_key() -> Value {
+        json!({ "type": "read", "key": 0 })
+    }
+
+    fn cas(from: i64, to: i64) -> Value {
+        json!({ "type": "cas", "key": 0, "from": from, "to": to })
+    }
+
+    #[test]
+    fn linearizable_checker_accepts_concurrent_overlap() {
+        // The read overlaps both writes, so it may see either.
+        let history = Builder::default()
+            .op("c1", (0, 10), write(1), ok("write"))
+            .op("c2", (5, 15), write(2), ok("write"))
+            .op("c3", (6, 7), read_key(), read_value(1))
+            .op("c3", (20, 21), read_key(), read_value(2))
+            .op("c1", (22, 23), cas(2, 3), ok("cas"));
+        assert_eq!(check_linearizable(&history.history), Ok(()));
+    }
+
+    #[test]
+    fn linearizable_checker_rejects_stale_reads() {
+        let stale = Builder::default()
+            .op("c1", (0, 1), write(1), ok("write"))
+            .op("c1", (2, 3), write(2), ok("write"))
+            .op("c2", (4, 5), read_key(), read_value(1));
+        assert!(check_linearizable(&stale.history).is_err());
+
+        let missing = Builder::default()
+            .op("c1", (0, 1), write(1), ok("write"))
+            .op("c2", (2, 3), read_key(), error(ErrorCode::KeyDoesNotExist));
+        assert!(check_linearizable(&missing.history).is_err());
+    }
+
+    #[test]
+    fn linearizable_checker_may_leave_out_indeterminate_calls() {
+        // The cas timed out, so the later read is free to miss it.
+        let history = Builder::default()
+            .op("c1", (0, 1), write(1), ok("write"))
+            .op("c1", (2, 3), cas(1, 2), error(ErrorCode::Timeout))
+            .op("c2", (4, 5), read_key(), read_value(1));
+        assert_eq!(check_linearizable(&history.history), Ok(()));
+
+        // Or to see it, however long after the timeout.
+        let history = Builder::default()
+            .op("c1", (0, 1), write(1), ok("write"))
+            .op("c1", (2, 3), cas(1, 2), error(ErrorCode::Timeout))
+            .op("c2", (4, 5), read_key(), read_value(2))
+            .op("c2", (6, 7), read_key(), read_value(2));
+        assert_eq!(check_linearizable(&history.history), Ok(()));
+    }
+}
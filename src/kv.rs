@@ -0,0 +1,520 @@
+use std::fmt;
+
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{CallOptions, Error, ErrorCode, Message, Node, Output, RpcError};
+
+/// The key/value services Maelstrom runs alongside the nodes under test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KvService {
+    LinKv,
+    SeqKv,
+    LwwKv,
+}
+
+impl KvService {
+    pub fn node_id(self) -> &'static str {
+        match self {
+            KvService::LinKv => "lin-kv",
+            KvService::SeqKv => "seq-kv",
+            KvService::LwwKv => "lww-kv",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum KvPayload {
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    WriteOk {},
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        create_if_not_exists: bool,
+    },
+    CasOk {},
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KvError {
+    KeyDoesNotExist,
+    /// A `cas` found a value other than `from`.
+    PreconditionFailed,
+    Rpc(RpcError),
+}
+
+impl KvError {
+    fn from_rpc(err: RpcError) -> Self {
+        match err {
+            RpcError::Remote(ref error) if error.code == ErrorCode::KeyDoesNotExist => {
+                KvError::KeyDoesNotExist
+            }
+            RpcError::Remote(ref error) if error.code == ErrorCode::PreconditionFailed => {
+                KvError::PreconditionFailed
+            }
+            err => KvError::Rpc(err),
+        }
+    }
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvError::KeyDoesNotExist => f.write_str("key does not exist"),
+            KvError::PreconditionFailed => f.write_str("precondition failed"),
+            KvError::Rpc(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for KvError {}
+
+impl From<KvError> for Error {
+    fn from(err: KvError) -> Self {
+        match err {
+            KvError::KeyDoesNotExist => Error::new(ErrorCode::KeyDoesNotExist, err.to_string()),
+            KvError::PreconditionFailed => {
+                Error::new(ErrorCode::PreconditionFailed, err.to_string())
+            }
+            KvError::Rpc(err) => err.into(),
+        }
+    }
+}
+
+/// Client for `lin-kv`, `seq-kv` and `lww-kv`. Requests go through the node's `Rpc` table, so
+/// the node must return it from `Node::rpc`.
+#[derive(Debug, Clone)]
+pub struct Kv {
+    service: KvService,
+    options: CallOptions,
+}
+
+impl Kv {
+    pub fn new(service: KvService) -> Self {
+        Self {
+            service,
+            options: CallOptions::default(),
+        }
+    }
+
+    pub fn with_options(mut self, options: CallOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn service(&self) -> KvService {
+        self.service
+    }
+
+    pub fn read<State, Payload, InjectedPayload, K, V, F>(
+        &self,
+        node: &mut State,
+        key: K,
+        output: &mut Output,
+        handler: F,
+    ) -> Result<()>
+    where
+        State: Node<Payload, InjectedPayload>,
+        K: Serialize,
+        V: DeserializeOwned,
+        F: FnOnce(&mut State, Result<V, KvError>, &mut Output) -> Result<()> + Send + 'static,
+    {
+        let payload = KvPayload::Read {
+            key: serde_json::to_value(key).context("serialize kv key")?,
+        };
+        self.call(node, payload, output, |node, reply, output| {
+            let value = match reply {
+                Ok(KvPayload::ReadOk { value }) => {
+                    serde_json::from_value(value).map_err(|err| {
+                        KvError::Rpc(RpcError::Malformed(err.to_string()))
+                    })
+                }
+                Ok(other) => Err(unexpected(other)),
+                Err(err) => Err(err),
+            };
+            handler(node, value, output)
+        })
+    }
+
+    pub fn write<State, Payload, InjectedPayload, K, V, F>(
+        &self,
+        node: &mut State,
+        key: K,
+        value: V,
+        output: &mut Output,
+        handler: F,
+    ) -> Result<()>
+    where
+        State: Node<Payload, InjectedPayload>,
+        K: Serialize,
+        V: Serialize,
+        F: FnOnce(&mut State, Result<(), KvError>, &mut Output) -> Result<()> + Send + 'static,
+    {
+        let payload = KvPayload::Write {
+            key: serde_json::to_value(key).context("serialize kv key")?,
+            value: serde_json::to_value(value).context("serialize kv value")?,
+        };
+        self.call(node, payload, output, |node, reply, output| {
+            let reply = reply.and_then(|reply| match reply {
+                KvPayload::WriteOk {} => Ok(()),
+                other => Err(unexpected(other)),
+            });
+            handler(node, reply, output)
+        })
+    }
+
+    /// Replaces `key`'s value with `to` if it is currently `from`.
+    pub fn cas<State, Payload, InjectedPayload, K, V, F>(
+        &self,
+        node: &mut State,
+        key: K,
+        from: V,
+        to: V,
+        output: &mut Output,
+        handler: F,
+    ) -> Result<()>
+    where
+        State: Node<Payload, InjectedPayload>,
+        K: Serialize,
+        V: Serialize,
+        F: FnOnce(&mut State, Result<(), KvError>, &mut Output) -> Result<()> + Send + 'static,
+    {
+        let payload = cas_payload(key, from, to, false)?;
+        self.call_cas(node, payload, output, handler)
+    }
+
+    /// Like `cas`, but a missing key is created holding `to` instead of failing with
+    /// `KeyDoesNotExist`.
+    pub fn cas_or_create<State, Payload, InjectedPayload, K, V, F>(
+        &self,
+        node: &mut State,
+        key: K,
+        from: V,
+        to: V,
+        output: &mut Output,
+        handler: F,
+    ) -> Result<()>
+    where
+        State: Node<Payload, InjectedPayload>,
+        K: Serialize,
+        V: Serialize,
+        F: FnOnce(&mut State, Result<(), KvError>, &mut Output) -> Result<()> + Send + 'static,
+    {
+        let payload = cas_payload(key, from, to, true)?;
+        self.call_cas(node, payload, output, handler)
+    }
+
+    fn call_cas<State, Payload, InjectedPayload, F>(
+        &self,
+        node: &mut State,
+        payload: KvPayload,
+        output: &mut Output,
+        handler: F,
+    ) -> Result<()>
+    where
+        State: Node<Payload, InjectedPayload>,
+        F: FnOnce(&mut State, Result<(), KvError>, &mut Output) -> Result<()> + Send + 'static,
+    {
+        self.call(node, payload, output, |node, reply, output| {
+            let reply = reply.and_then(|reply| match reply {
+                KvPayload::CasOk {} => Ok(()),
+                other => Err(unexpected(other)),
+            });
+            handler(node, reply, output)
+        })
+    }
+
+    fn call<State, Payload, InjectedPayload, F>(
+        &self,
+        node: &mut State,
+        payload: KvPayload,
+        output: &mut Output,
+        handler: F,
+    ) -> Result<()>
+    where
+        State: Node<Payload, InjectedPayload>,
+        F: FnOnce(&mut State, Result<KvPayload, KvError>, &mut Output) -> Result<()>
+            + Send
+            + 'static,
+    {
+        let msg = node.request(self.service.node_id().to_string(), payload);
+        let rpc = node.rpc().context("kv client needs the node to expose its rpc table")?;
+        rpc.call_with(
+            &msg,
+            output,
+            self.options.clone(),
+            |node, reply: Result<Message<KvPayload>, RpcError>, output| {
+                let reply = reply
+                    .map(|msg| msg.body.payload)
+                    .map_err(KvError::from_rpc);
+                handler(node, reply, output)
+            },
+        )
+    }
+}
+
+fn cas_payload(
+    key: impl Serialize,
+    from: impl Serialize,
+    to: impl Serialize,
+    create_if_not_exists: bool,
+) -> Result<KvPayload> {
+    Ok(KvPayload::Cas {
+        key: serde_json::to_value(key).context("serialize kv key")?,
+        from: serde_json::to_value(from).context("serialize kv value")?,
+        to: serde_json::to_value(to).context("serialize kv value")?,
+        create_if_not_exists,
+    })
+}
+
+fn unexpected(reply: KvPayload) -> KvError {
+    KvError::Rpc(RpcError::Malformed(format!(
+        "unexpected kv reply {reply:?}"
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{payload, Event, Identity, Init, Injector, Rpc, SimConfig, Simulation};
+
+    #[payload]
+    #[derive(Debug, Clone)]
+    enum Payload {
+        Read {
+            key: String,
+        },
+        Write {
+            key: String,
+            value: u64,
+        },
+        Cas {
+            key: String,
+            from: u64,
+            to: u64,
+            create: bool,
+        },
+    }
+
+    /// Turns each client request into the matching `lin-kv` call and keeps what it got back.
+    struct Client {
+        id: String,
+        msg_id: usize,
+        rpc: Rpc<Self>,
+        kv: Kv,
+        results: Vec<Result<Option<u64>, KvError>>,
+    }
+
+    impl Identity for Client {
+        fn next_msg_id(&mut self) -> usize {
+            let out = self.msg_id;
+            self.msg_id += 1;
+            out
+        }
+
+        fn node_id(&self) -> String {
+            self.id.clone()
+        }
+    }
+
+    impl Node<Payload> for Client {
+        fn from_init(init: Init, _tx: Injector<()>) -> Result<Self> {
+            Ok(Self {
+                id: init.node_id,
+                msg_id: 1,
+                rpc: Rpc::new(),
+                kv: Kv::new(KvService::LinKv),
+                results: Vec::new(),
+            })
+        }
+
+        fn rpc(&mut self) -> Option<&mut Rpc<Self>> {
+            Some(&mut self.rpc)
+        }
+
+        fn process_message(&mut self, event: Event<Payload>, output: &mut Output) -> Result<()> {
+            let Event::Message(msg) = event else {
+                return Ok(());
+            };
+            let kv = self.kv.clone();
+            match msg.body.payload {
+                Payload::Read { key } => kv.read(
+                    self,
+                    key,
+                    output,
+                    |node: &mut Self, value: Result<u64, _>, _| {
+                        node.results.push(value.map(Some));
+                        Ok(())
+                    },
+                ),
+                Payload::Write { key, value } => {
+                    kv.write(self, key, value, output, |node: &mut Self, result, _| {
+                        node.results.push(result.map(|()| None));
+                        Ok(())
+                    })
+                }
+                Payload::Cas {
+                    key,
+                    from,
+                    to,
+                    create,
+                } => {
+                    let handler = |node: &mut Self, result: Result<(), _>, _: &mut Output| {
+                        node.results.push(result.map(|()| None));
+                        Ok(())
+                    };
+                    if create {
+                        kv.cas_or_create(self, key, from, to, output, handler)
+                    } else {
+                        kv.cas(self, key, from, to, output, handler)
+                    }
+                }
+            }
+        }
+    }
+
+    /// An in-memory `lin-kv`. Writes to `"busy"` fail as temporarily unavailable.
+    fn lin_kv() -> impl FnMut(&Message<Value>) -> Value {
+        let mut store = HashMap::new();
+        store.insert("text".to_string(), json!("not a number"));
+        move |msg| {
+            let body = &msg.body.payload;
+            let key = body["key"].as_str().unwrap().to_string();
+            let error = |code, text| json!({ "type": "error", "code": code, "text": text });
+            match body["type"].as_str().unwrap() {
+                "read" => match store.get(&key) {
+                    Some(value) => json!({ "type": "read_ok", "value": value }),
+                    None => error(20, "not found"),
+                },
+                "write" if key == "busy" => error(11, "try again"),
+                "write" => {
+                    store.insert(key, body["value"].clone());
+                    json!({ "type": "write_ok" })
+                }
+                "cas" => match store.get(&key) {
+                    None if body["create_if_not_exists"] == json!(true) => {
+                        store.insert(key, body["to"].clone());
+                        json!({ "type": "cas_ok" })
+                    }
+                    None => error(20, "not found"),
+                    Some(value) if *value != body["from"] => error(22, "expected another value"),
+                    Some(_) => {
+                        store.insert(key, body["to"].clone());
+                        json!({ "type": "cas_ok" })
+                    }
+                },
+                other => panic!("lin-kv got {other}"),
+            }
+        }
+    }
+
+    fn sim() -> Simulation<Client, Payload> {
+        let mut sim = Simulation::new(1, SimConfig::default()).unwrap();
+        sim.add_service("lin-kv", lin_kv());
+        sim
+    }
+
+    /// Has `n1` run `op` and returns what its kv call came back with.
+    fn run(sim: &mut Simulation<Client, Payload>, op: Payload) -> Result<Option<u64>, KvError> {
+        sim.send("c1", "n1", op).unwrap();
+        sim.run_for(Duration::from_millis(100)).unwrap();
+        let results = &sim.node("n1").unwrap().results;
+        results
+            .last()
+            .cloned()
+            .expect("the kv call should have completed")
+    }
+
+    fn read(key: &str) -> Payload {
+        Payload::Read { key: key.into() }
+    }
+
+    fn cas(key: &str, from: u64, to: u64, create: bool) -> Payload {
+        Payload::Cas {
+            key: key.into(),
+            from,
+            to,
+            create,
+        }
+    }
+
+    #[test]
+    fn reads_decode_the_stored_value() {
+        let mut sim = sim();
+        let write = Payload::Write {
+            key: "x".into(),
+            value: 3,
+        };
+        assert_eq!(run(&mut sim, write), Ok(None));
+        assert_eq!(run(&mut sim, read("x")), Ok(Some(3)));
+        assert!(matches!(
+            run(&mut sim, read("text")),
+            Err(KvError::Rpc(RpcError::Malformed(_)))
+        ));
+    }
+
+    #[test]
+    fn service_errors_map_to_kv_errors() {
+        let mut sim = sim();
+        assert_eq!(run(&mut sim, read("x")), Err(KvError::KeyDoesNotExist));
+        assert_eq!(
+            run(&mut sim, cas("x", 0, 1, false)),
+            Err(KvError::KeyDoesNotExist)
+        );
+
+        let write = Payload::Write {
+            key: "x".into(),
+            value: 3,
+        };
+        assert_eq!(run(&mut sim, write), Ok(None));
+        assert_eq!(
+            run(&mut sim, cas("x", 0, 1, false)),
+            Err(KvError::PreconditionFailed)
+        );
+        assert_eq!(run(&mut sim, cas("x", 3, 4, false)), Ok(None));
+        assert_eq!(run(&mut sim, read("x")), Ok(Some(4)));
+
+        let busy = Payload::Write {
+            key: "busy".into(),
+            value: 1,
+        };
+        match run(&mut sim, busy) {
+            Err(KvError::Rpc(RpcError::Remote(error))) => {
+                assert_eq!(error.code, ErrorCode::TemporarilyUnavailable);
+            }
+            other => panic!("expected a remote error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn cas_or_create_creates_a_missing_key() {
+        let mut sim = sim();
+        assert_eq!(run(&mut sim, cas("x", 0, 1, true)), Ok(None));
+        assert_eq!(run(&mut sim, read("x")), Ok(Some(1)));
+        // Once the key exists it behaves like a plain cas.
+        assert_eq!(
+            run(&mut sim, cas("x", 0, 2, true)),
+            Err(KvError::PreconditionFailed)
+        );
+        assert_eq!(run(&mut sim, cas("x", 1, 2, true)), Ok(None));
+        assert_eq!(run(&mut sim, read("x")), Ok(Some(2)));
+    }
+}
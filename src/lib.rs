@@ -1,7 +1,57 @@
-use std::{io::{BufRead, StdoutLock, Write}, sync::mpsc::{self, Sender}, thread};
+use std::{collections::HashMap, fmt, io::Write, sync::{atomic::{AtomicUsize, Ordering}, mpsc::{self, RecvTimeoutError, SendError, Sender}, Arc, Mutex}, thread, time::{Duration, Instant}};
 
-use anyhow::{Context, Result};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use anyhow::{anyhow, Context, Result};
+use serde::{
+    de::{self, value::MapDeserializer, DeserializeOwned},
+    Deserialize, Serialize,
+};
+use serde_json::Value;
+
+// Lets `#[payload]` and the derives, which name this crate by path, be used inside it too.
+extern crate self as maelstrom_node;
+
+mod error;
+mod executor;
+mod history;
+mod kv;
+mod metrics;
+mod nemesis;
+mod pool;
+mod raft;
+mod rpc;
+mod sim;
+#[cfg(test)]
+mod testing;
+mod timer;
+mod trace;
+mod transport;
+mod tso;
+
+pub use error::{Error, ErrorCode};
+pub use executor::{async_main_loop, AsyncContext, AsyncNode, Call, Sleep};
+pub use history::{
+    check_broadcast, check_counter, check_linearizable, CheckError, History, Operation, Outcome,
+};
+pub use kv::{Kv, KvError, KvService};
+pub use maelstrom_node_derive::{payload, Identity, MessageType, TypedBody};
+pub use metrics::{Histogram, Metrics, Traffic, METRICS_ENV};
+pub use nemesis::{Latency, Nemesis};
+pub use pool::{
+    concurrent_main_loop, concurrent_main_loop_with, ConcurrentNode, Outbox, Sharded,
+};
+pub use raft::{
+    AppendEntries, AppendEntriesResult, LogEntry, NotLeader, Outbound, Raft, RaftConfig,
+    RaftRequest, RequestVote, RequestVoteResult,
+};
+pub use rpc::{Backoff, CallOptions, RetryPolicy, Rpc, RpcError};
+pub use sim::{SimConfig, Simulation};
+pub use timer::TimerId;
+pub use trace::TRACE_ENV;
+pub use transport::{Channel, ChannelLines, ChannelWriter, Output, Stdio, Streams, Transport};
+pub use tso::Tso;
+
+use timer::TimerWheel;
+use trace::Tracer;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message<Payload> {
@@ -29,6 +79,24 @@ impl<Payload> Message<Payload> {
     }
 }
 
+impl Message<Value> {
+    pub(crate) fn payload_type(&self) -> Option<&str> {
+        self.body.payload.get("type").and_then(Value::as_str)
+    }
+
+    pub(crate) fn decode<Payload: DeserializeOwned>(&self) -> serde_json::Result<Message<Payload>> {
+        Ok(Message {
+            src: self.src.clone(),
+            dest: self.dest.clone(),
+            body: Body {
+                id: self.body.id,
+                reply_to: self.body.reply_to,
+                payload: Payload::deserialize(&self.body.payload)?,
+            },
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Body<Payload> {
     #[serde(rename = "msg_id")]
@@ -70,13 +138,110 @@ pub enum Event<Payload, InjectedPayload = ()> {
     EOF,
 }
 
-pub trait Node<Payload, InjectedPayload = ()> {
-    fn from_init(init: Init, tx: Sender<Event<Payload, InjectedPayload>>) -> Result<Self>
+enum Input<InjectedPayload> {
+    Line(String),
+    Injected(InjectedPayload),
+    TimersChanged,
+    Eof,
+}
+
+/// The sending half of `main_loop`'s input queue, keeping count of what waits in it.
+struct InputSender<InjectedPayload> {
+    tx: Sender<Input<InjectedPayload>>,
+    depth: Arc<AtomicUsize>,
+}
+
+impl<InjectedPayload> Clone for InputSender<InjectedPayload> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            depth: self.depth.clone(),
+        }
+    }
+}
+
+impl<InjectedPayload> InputSender<InjectedPayload> {
+    fn send(&self, input: Input<InjectedPayload>) -> Result<(), SendError<Input<InjectedPayload>>> {
+        self.depth.fetch_add(1, Ordering::Relaxed);
+        self.tx.send(input).inspect_err(|_| {
+            self.depth.fetch_sub(1, Ordering::Relaxed);
+        })
+    }
+}
+
+/// Handle for pushing `Event::Injected` values into a running `main_loop`, either right away
+/// or from one of the loop's timers.
+pub struct Injector<InjectedPayload> {
+    tx: InputSender<InjectedPayload>,
+    timers: Arc<Mutex<TimerWheel<InjectedPayload>>>,
+}
+
+impl<InjectedPayload> Clone for Injector<InjectedPayload> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            timers: self.timers.clone(),
+        }
+    }
+}
+
+impl<InjectedPayload> Injector<InjectedPayload> {
+    pub fn inject(&self, payload: InjectedPayload) -> Result<()> {
+        self.tx
+            .send(Input::Injected(payload))
+            .map_err(|_| anyhow!("main loop is no longer running"))
+    }
+
+    /// Delivers `payload` once, `after` from now.
+    pub fn schedule_once(&self, after: Duration, payload: InjectedPayload) -> TimerId {
+        let id = self.timers.lock().expect("timer lock poisoned").schedule_once(after, payload);
+        self.wake();
+        id
+    }
+
+    /// Delivers a clone of `payload` every `period` until cancelled or the input ends.
+    pub fn schedule_every(&self, period: Duration, payload: InjectedPayload) -> TimerId
     where
-        Self: Sized;
+        InjectedPayload: Clone,
+    {
+        let id = self.timers.lock().expect("timer lock poisoned").schedule_every(period, payload);
+        self.wake();
+        id
+    }
+
+    pub fn cancel(&self, id: TimerId) -> bool {
+        self.timers.lock().expect("timer lock poisoned").cancel(id)
+    }
+
+    // The loop may be asleep waiting on a later timer, so make it look again.
+    fn wake(&self) {
+        let _ = self.tx.send(Input::TimersChanged);
+    }
+}
+
+/// A node's own id and the counter its outgoing `msg_id`s come from.
+/// `#[derive(Identity)]` implements it from the struct's `id` and `msg_id` fields.
+pub trait Identity {
     fn next_msg_id(&mut self) -> usize;
     fn node_id(&self) -> String;
-    fn process_message(&mut self, event: Event<Payload, InjectedPayload>, output: &mut StdoutLock) -> Result<()>;
+}
+
+/// The wire `type`s of a payload enum. `#[derive(MessageType)]` implements it for the usual
+/// `#[serde(tag = "type")]` enums, pairing each `Foo` request with a `FooOk` variant, and
+/// `#[payload]` adds that derive along with the serde ones.
+pub trait MessageType {
+    fn message_type(&self) -> &'static str;
+    /// The `type` of the reply to a request of `request_type`, if it expects one.
+    fn response_type_for(request_type: &str) -> Option<&'static str>
+    where
+        Self: Sized;
+}
+
+pub trait Node<Payload, InjectedPayload = ()>: Identity {
+    fn from_init(init: Init, tx: Injector<InjectedPayload>) -> Result<Self>
+    where
+        Self: Sized;
+    fn process_message(&mut self, event: Event<Payload, InjectedPayload>, output: &mut Output) -> Result<()>;
     fn reply(&mut self, msg: Message<Payload>, payload: Payload) -> Message<Payload> {
         let mut body = msg.body;
         body.reply_to = body.id;
@@ -88,63 +253,1149 @@ pub trait Node<Payload, InjectedPayload = ()> {
             body,
         }
     }
+    fn reply_error<Req>(&mut self, msg: Message<Req>, error: Error) -> Message<Error> {
+        Message {
+            src: self.node_id(),
+            dest: msg.src,
+            body: Body {
+                id: Some(self.next_msg_id()),
+                reply_to: msg.body.id,
+                payload: error,
+            },
+        }
+    }
+    fn process_error(&mut self, _msg: Message<Error>, _output: &mut Output) -> Result<()> {
+        Ok(())
+    }
+    fn request<Req>(&mut self, dest: String, payload: Req) -> Message<Req> {
+        Message {
+            src: self.node_id(),
+            dest,
+            body: Body::new(Some(self.next_msg_id()), payload),
+        }
+    }
+    fn rpc(&mut self) -> Option<&mut Rpc<Self>>
+    where
+        Self: Sized,
+    {
+        None
+    }
 }
 
-pub fn main_loop<State, Payload, InjectedPayload>() -> Result<()>
-where
-    State: Node<Payload, InjectedPayload>,
-    Payload: DeserializeOwned + Send + 'static,
-    InjectedPayload: Send + 'static,
-{
-    let (tx, rx) = mpsc::channel();
+/// Answers a line that isn't a well-formed message with `malformed-request`, if it at least
+/// says who sent it and under which `msg_id`. Those are read from the JSON when the line
+/// parses, and otherwise looked for in its raw text.
+pub(crate) fn reject_malformed<State: Identity>(
+    state: &mut State,
+    line: &str,
+    err: serde_json::Error,
+    output: &mut Output,
+) -> Result<()> {
+    let (src, id) = match serde_json::from_str::<Value>(line) {
+        Ok(value) => (
+            value.get("src").and_then(Value::as_str).map(str::to_string),
+            value.pointer("/body/msg_id").and_then(Value::as_u64),
+        ),
+        Err(_) => (
+            raw_field(line, "src").map(str::to_string),
+            raw_field(line, "msg_id").and_then(|id| id.parse().ok()),
+        ),
+    };
+    let (Some(src), Some(id)) = (src, id) else {
+        eprintln!("dropping malformed input: {err}");
+        return Ok(());
+    };
+    Message {
+        src: state.node_id(),
+        dest: src,
+        body: Body {
+            id: Some(state.next_msg_id()),
+            reply_to: Some(id as usize),
+            payload: Error::new(ErrorCode::MalformedRequest, err.to_string()),
+        },
+    }
+    .send(output)
+}
 
-    let stdin = std::io::stdin().lock();
-    let mut stdin = stdin.lines();
-    let mut stdout = std::io::stdout().lock();
-
-    let init_msg: Message<InitPayload> = serde_json::from_str(
-        &stdin
-            .next()
-            .expect("should receive an init message")
-            .context("failed to read init msg from stdin")?,
-    )
-    .context("init message could not be deserialized")?;
-    let InitPayload::Init(init) = init_msg.body.payload else {
-        panic!("first message should be init");
+/// The string or number after the first `"key":` in `text`, for lines that aren't JSON. A
+/// nested field of the same name may come first, so this is only a best guess.
+fn raw_field<'a>(text: &'a str, key: &str) -> Option<&'a str> {
+    let quoted_key = format!("\"{key}\"");
+    let rest = &text[text.find(&quoted_key)? + quoted_key.len()..];
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start();
+    if let Some(string) = rest.strip_prefix('"') {
+        return string.find('"').map(|end| &string[..end]);
+    }
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    (end > 0).then(|| &rest[..end])
+}
+
+/// Answers a message whose payload the node can't decode with `not-supported` if its type is
+/// unknown, or `malformed-request` if it is known but its fields don't fit.
+pub(crate) fn reject_payload<State: Identity, Payload: DeserializeOwned>(
+    state: &mut State,
+    msg: Message<Value>,
+    err: serde_json::Error,
+    output: &mut Output,
+) -> Result<()> {
+    // Replies can't be answered, and requests without a msg_id have nothing to answer to.
+    if msg.body.reply_to.is_some() || msg.body.id.is_none() {
+        eprintln!("dropping undecodable message from {}: {err}", msg.src);
+        return Ok(());
+    }
+    let kind = msg.payload_type().unwrap_or_default();
+    let known = payload_types::<Payload>().is_none_or(|types| types.contains(&kind));
+    let error = if !known {
+        Error::new(ErrorCode::NotSupported, format!("unsupported message type {kind:?}"))
+    } else {
+        Error::new(ErrorCode::MalformedRequest, err.to_string())
     };
+    Message {
+        src: state.node_id(),
+        dest: msg.src,
+        body: Body {
+            id: Some(state.next_msg_id()),
+            reply_to: msg.body.id,
+            payload: error,
+        },
+    }
+    .send(output)
+}
+
+/// The `type`s a `#[serde(tag = "type")]` enum accepts, or `None` if `Payload` isn't one. Serde
+/// only tells them to a deserializer whose input names a type the enum doesn't have, so this
+/// offers it an empty one and keeps the list the resulting error is built from.
+fn payload_types<Payload: DeserializeOwned>() -> Option<&'static [&'static str]> {
+    let probe = MapDeserializer::<_, TypeProbe>::new(std::iter::once(("type", "")));
+    match Payload::deserialize(probe) {
+        Err(TypeProbe(types)) => types,
+        Ok(_) => None,
+    }
+}
+
+#[derive(Debug)]
+struct TypeProbe(Option<&'static [&'static str]>);
+
+impl fmt::Display for TypeProbe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("probing payload types")
+    }
+}
+
+impl std::error::Error for TypeProbe {}
+
+impl de::Error for TypeProbe {
+    fn custom<T: fmt::Display>(_msg: T) -> Self {
+        TypeProbe(None)
+    }
+
+    fn unknown_variant(_variant: &str, expected: &'static [&'static str]) -> Self {
+        TypeProbe(Some(expected))
+    }
+}
 
+pub(crate) fn init_ok(node_id: String, init_msg: &Message<Value>, id: usize) -> Message<InitPayload> {
     Message {
-        src: init.node_id.clone(),
+        src: node_id,
         dest: init_msg.src.clone(),
         body: Body {
-            id: Some(0),
+            id: Some(id),
             reply_to: init_msg.body.id,
             payload: InitPayload::InitOk,
         },
     }
-    .send(&mut stdout)?;
+}
+
+/// Reads input until an `init` arrives, returning it along with its raw line, or `None` if
+/// the input ends first. There is no node to hand anything else to yet, so other requests are
+/// answered with `temporarily-unavailable` for their clients to retry.
+fn await_init(
+    lines: &mut impl Iterator<Item = std::io::Result<String>>,
+    output: &mut Output,
+) -> Result<Option<(Init, Message<Value>, String)>> {
+    for line in lines {
+        let line = line.context("failed to read init msg from input")?;
+        let msg: Message<Value> = match serde_json::from_str(&line) {
+            Ok(msg) => msg,
+            Err(err) => {
+                eprintln!("dropping malformed input before init: {err}");
+                continue;
+            }
+        };
+        let error = match msg.decode::<InitPayload>() {
+            Ok(Message {
+                body: Body {
+                    payload: InitPayload::Init(init),
+                    ..
+                },
+                ..
+            }) => return Ok(Some((init, msg, line))),
+            Err(err) if msg.payload_type() == Some("init") => {
+                Error::new(ErrorCode::MalformedRequest, err.to_string())
+            }
+            _ => Error::new(ErrorCode::TemporarilyUnavailable, "node is not initialized yet"),
+        };
+        if msg.body.reply_to.is_some() || msg.body.id.is_none() {
+            eprintln!("dropping message from {} that arrived before init", msg.src);
+            continue;
+        }
+        Message {
+            src: msg.dest,
+            dest: msg.src,
+            body: Body {
+                id: None,
+                reply_to: msg.body.id,
+                payload: error,
+            },
+        }
+        .send(output)?;
+        output.flush_tick()?;
+    }
+    Ok(None)
+}
+
+/// Brings the node's outstanding requests and timers up to `now`, handing it every timer that
+/// came due.
+pub(crate) fn advance<State, Payload, InjectedPayload>(
+    state: &mut State,
+    timers: &Mutex<TimerWheel<InjectedPayload>>,
+    now: Instant,
+    output: &mut Output,
+) -> Result<()>
+where
+    State: Node<Payload, InjectedPayload>,
+{
+    rpc::tick(state, now, output)?;
+    let fired = timers.lock().expect("timer lock poisoned").fire(now);
+    for payload in fired {
+        output.trace_injected(true);
+        state.process_message(Event::Injected(payload), output).context("process message failed")?;
+    }
+    Ok(())
+}
+
+/// Parses one line of input and hands it to whichever rpc handler or node callback it is for.
+pub(crate) fn handle_line<State, Payload, InjectedPayload>(
+    state: &mut State,
+    line: &str,
+    output: &mut Output,
+) -> Result<()>
+where
+    State: Node<Payload, InjectedPayload>,
+    Payload: DeserializeOwned,
+{
+    output.observe_recv(line);
+    let msg: Message<Value> = match serde_json::from_str(line) {
+        Ok(msg) => msg,
+        Err(err) => return reject_malformed(state, line, err, output),
+    };
+    if msg.payload_type() == Some("init") {
+        // The node is already running, so a repeated init only needs acknowledging again.
+        let id = state.next_msg_id();
+        return init_ok(state.node_id(), &msg, id).send(output);
+    }
+    let Some(msg) = rpc::route_reply(state, msg, output)? else {
+        return Ok(());
+    };
+    if msg.payload_type() == Some("error") {
+        match msg.decode() {
+            Ok(error) => state.process_error(error, output).context("process error failed")?,
+            Err(err) => eprintln!("dropping unreadable error message from {}: {err}", msg.src),
+        }
+        return Ok(());
+    }
+    match msg.decode() {
+        Ok(msg) => state.process_message(Event::Message(msg), output).context("process message failed"),
+        Err(err) => reject_payload::<_, Payload>(state, msg, err, output),
+    }
+}
+
+/// Whether the environment variable `name` is set to anything but `0` or `false`.
+fn env_flag(name: &str) -> bool {
+    std::env::var(name).is_ok_and(|value| !matches!(value.as_str(), "" | "0" | "false"))
+}
+
+pub fn main_loop<State, Payload, InjectedPayload>() -> Result<()>
+where
+    State: Node<Payload, InjectedPayload>,
+    Payload: DeserializeOwned,
+    InjectedPayload: Send + 'static,
+{
+    main_loop_with::<State, Payload, InjectedPayload, _>(Stdio)
+}
+
+/// Runs a node like `main_loop`, reading and writing through `transport` instead of stdio.
+pub fn main_loop_with<State, Payload, InjectedPayload, T>(transport: T) -> Result<()>
+where
+    State: Node<Payload, InjectedPayload>,
+    Payload: DeserializeOwned,
+    InjectedPayload: Send + 'static,
+    T: Transport,
+{
+    run_loop(transport, State::from_init)
+}
+
+/// Opens `transport` and answers its `init`, setting up tracing and metrics for the node it
+/// names. `None` if the input ended before an `init` arrived.
+pub(crate) fn start<T: Transport>(transport: T) -> Result<Option<(T::Lines, Output, Init)>> {
+    let (mut lines, writer) = transport.open().context("open transport")?;
+    let mut output = Output::new(writer);
 
-    let mut state: State = Node::from_init(init, tx.clone()).context("node init failed")?;
+    let Some((init, init_msg, init_line)) = await_init(&mut lines, &mut output)? else {
+        return Ok(None);
+    };
+    output.set_tracer(Tracer::from_env(&init.node_id));
+    output.set_metrics(env_flag(METRICS_ENV).then(Metrics::default));
+    output.observe_recv(&init_line);
+    init_ok(init.node_id.clone(), &init_msg, 0).send(&mut output)?;
+    Ok(Some((lines, output, init)))
+}
+
+/// The body of `main_loop_with`, building the node with `make_state` rather than `from_init`.
+fn run_loop<State, Payload, InjectedPayload, T>(
+    transport: T,
+    make_state: impl FnOnce(Init, Injector<InjectedPayload>) -> Result<State>,
+) -> Result<()>
+where
+    State: Node<Payload, InjectedPayload>,
+    Payload: DeserializeOwned,
+    InjectedPayload: Send + 'static,
+    T: Transport,
+{
+    let Some((lines, mut output, init)) = start(transport)? else {
+        return Ok(());
+    };
+    let (tx, rx) = mpsc::channel();
+    let depth = Arc::new(AtomicUsize::new(0));
+    let tx = InputSender {
+        tx,
+        depth: depth.clone(),
+    };
+
+    let timers = Arc::new(Mutex::new(TimerWheel::new(Instant::now())));
+    let injector = Injector {
+        tx: tx.clone(),
+        timers: timers.clone(),
+    };
+    let mut state = make_state(init, injector).context("node init failed")?;
 
-    drop(stdin);
     let join_handler = thread::spawn(move|| {
-        let stdin = std::io::stdin().lock();
-        for line in stdin.lines() {
-            let input = line.context("get stdin")?;
-            let input = serde_json::from_str(&input).context("serialize input")?;
-            if tx.send(Event::Message(input)).is_err() {
+        for line in lines {
+            let input = line.context("read input")?;
+            if tx.send(Input::Line(input)).is_err() {
                 return Ok::<_, anyhow::Error>(());
             }
         }
-        let _ = tx.send(Event::EOF);
+        let _ = tx.send(Input::Eof);
         Ok(())
     });
 
-    for input in rx {
-        state.process_message(input, &mut stdout).context("process message failed")?;
-    }
+    // Whatever the node sent before failing still goes out, so peers see its last replies.
+    // Flushing here rather than leaving it to the writer's drop reports a failed write and
+    // counts the tick.
+    let served = serve(&mut state, &rx, &timers, &depth, &mut output);
+    let flushed = output.flush_tick();
+    served?;
+    flushed?;
 
     join_handler.join().expect("stdin thread panicked")?;
 
     Ok(())
 }
+
+/// `run_loop`'s turns: handles inputs, timers and rpc deadlines until EOF or an error.
+fn serve<State, Payload, InjectedPayload>(
+    state: &mut State,
+    rx: &mpsc::Receiver<Input<InjectedPayload>>,
+    timers: &Mutex<TimerWheel<InjectedPayload>>,
+    depth: &AtomicUsize,
+    output: &mut Output,
+) -> Result<()>
+where
+    State: Node<Payload, InjectedPayload>,
+    Payload: DeserializeOwned,
+{
+    loop {
+        // Everything the last turn sent goes out before waiting on the next one.
+        output.flush_tick()?;
+        let wakeup = [
+            state.rpc().and_then(|rpc| rpc.next_wakeup()),
+            timers.lock().expect("timer lock poisoned").next_wakeup(),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let timeout = wakeup.map(|wakeup| wakeup.saturating_duration_since(Instant::now()));
+        let input = match timeout {
+            Some(timeout) => match rx.recv_timeout(timeout) {
+                Ok(input) => Some(input),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            },
+            None => match rx.recv() {
+                Ok(input) => Some(input),
+                Err(_) => break,
+            },
+        };
+        advance(state, timers, Instant::now(), output)?;
+        let Some(input) = input else {
+            continue;
+        };
+        output.record_queue_depth(depth.fetch_sub(1, Ordering::Relaxed));
+
+        let event = match input {
+            Input::Line(line) => {
+                handle_line(state, &line, output)?;
+                continue;
+            }
+            Input::Injected(payload) => {
+                output.trace_injected(false);
+                Event::Injected(payload)
+            }
+            Input::TimersChanged => continue,
+            Input::Eof => {
+                timers.lock().expect("timer lock poisoned").clear();
+                Event::EOF
+            }
+        };
+        let eof = matches!(event, Event::EOF);
+        state.process_message(event, output).context("process message failed")?;
+        if eof {
+            output.flush_tick()?;
+            output.dump_metrics(&state.node_id());
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// A message body with a fixed wire `type`, so it can be registered with `NodeBuilder::on` or
+/// sent from a `HandlerContext` without wrapping it in a payload enum. `#[derive(TypedBody)]`
+/// names it after the struct, in snake_case.
+pub trait TypedBody {
+    const TYPE: &'static str;
+}
+
+type Handler<State> = Box<dyn FnMut(&mut HandlerContext<'_, State>, Message<Value>) -> Result<()>>;
+type MakeState<State> = Box<dyn FnOnce(&Init) -> Result<State>>;
+
+/// Builds a node out of one handler per request type instead of a `Node` impl with one big
+/// `match`. Requests nothing handles are answered with `not-supported`.
+///
+/// Protocol modules can each register their handlers on the same builder through `with`,
+/// keeping whatever state they need in a part of the shared `State`.
+pub struct NodeBuilder<State> {
+    make_state: MakeState<State>,
+    handlers: HashMap<&'static str, Handler<State>>,
+}
+
+impl<State: 'static> NodeBuilder<State> {
+    /// Starts a node whose state is made by `make_state` once `init` arrives.
+    pub fn new(make_state: impl FnOnce(&Init) -> Result<State> + 'static) -> Self {
+        Self {
+            make_state: Box::new(make_state),
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// Handles requests of type `Req::TYPE` with `handler`, replacing any earlier handler for
+    /// them. Requests that don't deserialize as `Req` are answered with `malformed-request`.
+    ///
+    /// Bodies the node schedules for itself through `HandlerContext::schedule_once` or
+    /// `schedule_every` are routed the same way, with the node as their sender.
+    pub fn on<Req>(
+        mut self,
+        mut handler: impl FnMut(&mut HandlerContext<'_, State>, Req) -> Result<()> + 'static,
+    ) -> Self
+    where
+        Req: TypedBody + DeserializeOwned,
+    {
+        let handler = move |ctx: &mut HandlerContext<'_, State>, msg: Message<Value>| {
+            match Req::deserialize(&msg.body.payload) {
+                Ok(req) => handler(ctx, req),
+                Err(err) => {
+                    ctx.reply_error(Error::new(ErrorCode::MalformedRequest, err.to_string()))
+                }
+            }
+        };
+        self.handlers.insert(Req::TYPE, Box::new(handler));
+        self
+    }
+
+    /// Registers a whole module's handlers, as in `builder.with(broadcast).with(kv)`.
+    pub fn with(self, module: impl FnOnce(Self) -> Self) -> Self {
+        module(self)
+    }
+
+    pub fn run(self) -> Result<()> {
+        self.run_with(Stdio)
+    }
+
+    /// Runs the node like `main_loop_with`, through `transport`.
+    pub fn run_with<T: Transport>(self, transport: T) -> Result<()> {
+        run_loop::<Routed<State>, Value, Value, T>(transport, move |init, injector| {
+            Ok(Routed {
+                handlers: self.handlers,
+                parts: Parts {
+                    state: (self.make_state)(&init)?,
+                    node_id: init.node_id,
+                    node_ids: init.node_ids,
+                    msg_id: 1,
+                    rpc: Rpc::new(),
+                    injector,
+                },
+            })
+        })
+    }
+}
+
+/// What a `NodeBuilder` handler gets alongside its request: the node's state, and ways to
+/// answer the request, message or call other nodes, and schedule messages to itself.
+pub struct HandlerContext<'a, State> {
+    pub state: &'a mut State,
+    node_id: &'a str,
+    node_ids: &'a [String],
+    msg_id: &'a mut usize,
+    rpc: &'a mut Rpc<Routed<State>>,
+    injector: &'a Injector<Value>,
+    src: String,
+    request_id: Option<usize>,
+    output: &'a mut Output,
+}
+
+impl<State> HandlerContext<'_, State> {
+    pub fn node_id(&self) -> &str {
+        self.node_id
+    }
+
+    pub fn node_ids(&self) -> &[String] {
+        self.node_ids
+    }
+
+    /// Who sent the request being handled.
+    pub fn src(&self) -> &str {
+        &self.src
+    }
+
+    pub fn next_msg_id(&mut self) -> usize {
+        let out = *self.msg_id;
+        *self.msg_id += 1;
+        out
+    }
+
+    pub fn send<Body: TypedBody + Serialize>(&mut self, dest: &str, body: Body) -> Result<()> {
+        let payload = typed_payload(body)?;
+        self.send_payload(dest.to_string(), None, payload)
+    }
+
+    /// Answers the request being handled. Does nothing if it had no `msg_id` to answer to.
+    pub fn reply<Body: TypedBody + Serialize>(&mut self, body: Body) -> Result<()> {
+        let Some(request_id) = self.request_id else {
+            return Ok(());
+        };
+        let payload = typed_payload(body)?;
+        self.send_payload(self.src.clone(), Some(request_id), payload)
+    }
+
+    pub fn reply_error(&mut self, error: Error) -> Result<()> {
+        let Some(request_id) = self.request_id else {
+            return Ok(());
+        };
+        let payload = serde_json::to_value(error).context("serialize error")?;
+        self.send_payload(self.src.clone(), Some(request_id), payload)
+    }
+
+    /// Sends `body` to `dest` as an rpc and calls `handler` with its `Resp` reply, or with the
+    /// `RpcError` the call ended in. Inside `handler`, `reply` still answers the request being
+    /// handled now, so a handler can answer its client once a peer has answered it.
+    pub fn call<Req, Resp>(
+        &mut self,
+        dest: &str,
+        body: Req,
+        options: CallOptions,
+        handler: impl FnOnce(&mut HandlerContext<'_, State>, Result<Resp, RpcError>) -> Result<()>
+            + Send
+            + 'static,
+    ) -> Result<()>
+    where
+        Req: TypedBody + Serialize,
+        Resp: TypedBody + DeserializeOwned,
+    {
+        let body = Body::new(Some(self.next_msg_id()), typed_payload(body)?);
+        let msg = Message::new(self.node_id.to_string(), dest.to_string(), body);
+        let (src, request_id) = (self.src.clone(), self.request_id);
+        let handler = move |node: &mut Routed<State>,
+                            reply: Result<Message<Value>, RpcError>,
+                            output: &mut Output| {
+            let reply = reply.and_then(|reply| {
+                let kind = reply.payload_type().unwrap_or_default();
+                if kind != Resp::TYPE {
+                    let expected = Resp::TYPE;
+                    return Err(RpcError::Malformed(format!(
+                        "expected a {expected} reply, got {kind}"
+                    )));
+                }
+                Resp::deserialize(reply.body.payload)
+                    .map_err(|err| RpcError::Malformed(err.to_string()))
+            });
+            handler(&mut node.parts.context(src, request_id, output), reply)
+        };
+        self.rpc
+            .call_with(&msg, &mut *self.output, options, handler)
+    }
+
+    /// Hands `body` to this node's own handler for `Body::TYPE` once, `after` from now.
+    pub fn schedule_once<Body>(&self, after: Duration, body: Body) -> Result<TimerId>
+    where
+        Body: TypedBody + Serialize,
+    {
+        Ok(self.injector.schedule_once(after, typed_payload(body)?))
+    }
+
+    /// Hands `body` to this node's own handler for `Body::TYPE` every `period` until cancelled.
+    pub fn schedule_every<Body>(&self, period: Duration, body: Body) -> Result<TimerId>
+    where
+        Body: TypedBody + Serialize,
+    {
+        Ok(self.injector.schedule_every(period, typed_payload(body)?))
+    }
+
+    pub fn cancel(&self, id: TimerId) -> bool {
+        self.injector.cancel(id)
+    }
+
+    fn send_payload(
+        &mut self,
+        dest: String,
+        reply_to: Option<usize>,
+        payload: Value,
+    ) -> Result<()> {
+        let body = Body {
+            id: Some(self.next_msg_id()),
+            reply_to,
+            payload,
+        };
+        Message::new(self.node_id.to_string(), dest, body).send(self.output)
+    }
+}
+
+fn typed_payload<Body: TypedBody + Serialize>(body: Body) -> Result<Value> {
+    let mut payload = serde_json::to_value(body).context("serialize message body")?;
+    let Value::Object(fields) = &mut payload else {
+        return Err(anyhow!("{} must serialize as a map", Body::TYPE));
+    };
+    fields.insert("type".to_string(), Body::TYPE.into());
+    Ok(payload)
+}
+
+/// The `Node` a `NodeBuilder` runs, routing each message to its type's handler.
+struct Routed<State> {
+    handlers: HashMap<&'static str, Handler<State>>,
+    parts: Parts<State>,
+}
+
+/// Everything of a `Routed` node its handlers reach through their `HandlerContext`.
+struct Parts<State> {
+    state: State,
+    node_id: String,
+    node_ids: Vec<String>,
+    msg_id: usize,
+    rpc: Rpc<Routed<State>>,
+    injector: Injector<Value>,
+}
+
+impl<State> Parts<State> {
+    fn context<'a>(
+        &'a mut self,
+        src: String,
+        request_id: Option<usize>,
+        output: &'a mut Output,
+    ) -> HandlerContext<'a, State> {
+        HandlerContext {
+            state: &mut self.state,
+            node_id: &self.node_id,
+            node_ids: &self.node_ids,
+            msg_id: &mut self.msg_id,
+            rpc: &mut self.rpc,
+            injector: &self.injector,
+            src,
+            request_id,
+            output,
+        }
+    }
+}
+
+impl<State> Identity for Routed<State> {
+    fn next_msg_id(&mut self) -> usize {
+        let out = self.parts.msg_id;
+        self.parts.msg_id += 1;
+        out
+    }
+
+    fn node_id(&self) -> String {
+        self.parts.node_id.clone()
+    }
+}
+
+impl<State> Node<Value, Value> for Routed<State> {
+    // `Routed` is private and only ever built by `NodeBuilder::run_with` from its handlers,
+    // which `from_init` has no way to reach.
+    fn from_init(_init: Init, _tx: Injector<Value>) -> Result<Self> {
+        Err(anyhow!("nodes with routed handlers are started by NodeBuilder::run"))
+    }
+
+    fn rpc(&mut self) -> Option<&mut Rpc<Self>> {
+        Some(&mut self.parts.rpc)
+    }
+
+    fn process_message(&mut self, event: Event<Value, Value>, output: &mut Output) -> Result<()> {
+        let (msg, scheduled) = match event {
+            Event::Message(msg) => (msg, false),
+            Event::Injected(payload) => {
+                let node_id = self.parts.node_id.clone();
+                let msg = Message::new(node_id.clone(), node_id, Body::new(None, payload));
+                (msg, true)
+            }
+            Event::EOF => return Ok(()),
+        };
+        let kind = msg.payload_type().unwrap_or_default().to_string();
+        let stray_reply = msg.body.reply_to.is_some();
+        let mut ctx = self.parts.context(msg.src.clone(), msg.body.id, output);
+        match self.handlers.get_mut(kind.as_str()) {
+            Some(handler) => handler(&mut ctx, msg),
+            None if scheduled => Err(anyhow!("no handler for the scheduled {kind:?}")),
+            // Stray replies have nobody to be answered by.
+            None if stray_reply => Ok(()),
+            None => ctx.reply_error(Error::new(
+                ErrorCode::NotSupported,
+                format!("unsupported message type {kind:?}"),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use std::sync::mpsc::Receiver;
+
+    use super::*;
+    use crate::testing::{Broadcaster, Payload};
+
+    #[test]
+    fn payload_types_lists_the_enum_variants() {
+        let types = payload_types::<Payload>().unwrap();
+        assert!(types.contains(&"broadcast") && types.contains(&"read_ok"));
+        assert_eq!(payload_types::<Value>(), None);
+    }
+
+    #[payload]
+    #[derive(Debug, Clone)]
+    enum Renamed {
+        #[serde(rename = "custom")]
+        Thing {},
+        ThingOk { value: u64 },
+        Other,
+    }
+
+    #[derive(Identity)]
+    struct Named {
+        #[node_id]
+        name: String,
+        msg_id: usize,
+    }
+
+    #[test]
+    fn message_types_follow_serde_and_pair_requests_with_ok_replies() {
+        assert_eq!(Payload::Read.message_type(), "read");
+        assert_eq!(Payload::ReadOk { messages: Vec::new() }.message_type(), "read_ok");
+        assert_eq!(Payload::response_type_for("broadcast"), Some("broadcast_ok"));
+        assert_eq!(Payload::response_type_for("read_ok"), None);
+
+        assert_eq!(Renamed::Thing {}.message_type(), "custom");
+        assert_eq!(Renamed::response_type_for("custom"), Some("thing_ok"));
+        assert_eq!(Renamed::response_type_for("other"), None);
+        let wire = serde_json::to_value(Renamed::ThingOk { value: 1 }).unwrap();
+        assert_eq!(wire, json!({"type": "thing_ok", "value": 1}));
+        assert!(matches!(serde_json::from_value(json!({"type": "other"})), Ok(Renamed::Other)));
+    }
+
+    #[test]
+    fn identity_counts_msg_ids_from_the_marked_fields() {
+        let mut node = Named {
+            name: "n1".to_string(),
+            msg_id: 3,
+        };
+        assert_eq!(node.node_id(), "n1");
+        assert_eq!((node.next_msg_id(), node.next_msg_id()), (3, 4));
+    }
+
+    /// Arms timers when asked, and tells `c1` each time one fires.
+    struct Ticker {
+        id: String,
+        msg_id: usize,
+        tx: Injector<()>,
+    }
+
+    impl Identity for Ticker {
+        fn next_msg_id(&mut self) -> usize {
+            self.msg_id += 1;
+            self.msg_id
+        }
+
+        fn node_id(&self) -> String {
+            self.id.clone()
+        }
+    }
+
+    impl Node<Value> for Ticker {
+        fn from_init(init: Init, tx: Injector<()>) -> Result<Self> {
+            Ok(Self {
+                id: init.node_id,
+                msg_id: 0,
+                tx,
+            })
+        }
+
+        fn process_message(&mut self, event: Event<Value>, output: &mut Output) -> Result<()> {
+            match event {
+                Event::Message(msg) if msg.payload_type() == Some("every") => {
+                    self.tx.schedule_every(Duration::from_millis(5), ());
+                }
+                // Schedules from another thread while the loop is asleep with nothing to do.
+                Event::Message(_) => {
+                    let tx = self.tx.clone();
+                    thread::spawn(move || {
+                        thread::sleep(Duration::from_millis(300));
+                        tx.schedule_once(Duration::from_millis(250), ());
+                    });
+                }
+                Event::Injected(()) => {
+                    self.request("c1".to_string(), json!({"type": "tick"})).send(output)?;
+                }
+                Event::EOF => {}
+            }
+            Ok(())
+        }
+    }
+
+    fn start_ticker() -> (Sender<String>, Receiver<String>, thread::JoinHandle<Result<()>>) {
+        let (transport, input, output) = Channel::pair();
+        let node = thread::spawn(move || main_loop_with::<Ticker, Value, (), _>(transport));
+        let init = json!({"src": "c0", "dest": "n1", "body": {
+            "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"],
+        }});
+        input.send(init.to_string()).unwrap();
+        let init_ok = output.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(init_ok.contains("init_ok"));
+        (input, output, node)
+    }
+
+    fn request(kind: &str) -> String {
+        json!({"src": "c1", "dest": "n1", "body": {"type": kind, "msg_id": 2}}).to_string()
+    }
+
+    #[test]
+    fn timers_scheduled_from_another_thread_count_from_then() {
+        let (input, output, node) = start_ticker();
+        let armed = Instant::now();
+        input.send(request("arm")).unwrap();
+        let tick = output.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert!(tick.contains("tick"));
+        assert!(armed.elapsed() >= Duration::from_millis(540));
+
+        drop(input);
+        node.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn periodic_timers_stop_at_eof() {
+        let (input, output, node) = start_ticker();
+        input.send(request("every")).unwrap();
+        for _ in 0..3 {
+            let tick = output.recv_timeout(Duration::from_secs(1)).unwrap();
+            assert!(tick.contains("tick"));
+        }
+
+        // The timer is still armed, so the loop only returns if EOF clears it.
+        drop(input);
+        node.join().unwrap().unwrap();
+    }
+
+    #[derive(Serialize, Deserialize, TypedBody)]
+    struct Echo {
+        echo: String,
+    }
+
+    #[derive(Serialize, Deserialize, TypedBody)]
+    struct EchoOk {
+        echo: String,
+    }
+
+    /// Asks `n2` to echo, and answers with what it said.
+    #[derive(Serialize, Deserialize, TypedBody)]
+    struct Relay {
+        echo: String,
+    }
+
+    #[derive(Serialize, Deserialize, TypedBody)]
+    struct RelayOk {
+        echo: String,
+    }
+
+    #[derive(Serialize, Deserialize, TypedBody)]
+    #[serde(rename = "later")]
+    struct Schedule {}
+
+    #[derive(Serialize, Deserialize, TypedBody)]
+    struct Fired {
+        count: u64,
+    }
+
+    #[derive(Serialize, Deserialize, TypedBody)]
+    struct Quit {}
+
+    #[derive(Serialize, Deserialize, TypedBody)]
+    struct QuitOk {}
+
+    fn start_routed() -> (Sender<String>, Receiver<String>, thread::JoinHandle<Result<()>>) {
+        let (transport, input, output) = Channel::pair();
+        let node = thread::spawn(move || {
+            NodeBuilder::new(|_| Ok(0))
+                .on(|ctx, Echo { echo }| ctx.reply(EchoOk { echo }))
+                .on(|ctx, Relay { echo }| {
+                    let options = CallOptions::default();
+                    ctx.call("n2", Echo { echo }, options, |ctx, reply| match reply {
+                        Ok(EchoOk { echo }) => ctx.reply(RelayOk { echo }),
+                        Err(err) => ctx.reply_error(err.into()),
+                    })
+                })
+                .on(|ctx, Schedule {}| {
+                    ctx.schedule_once(Duration::from_millis(10), Fired { count: 0 })?;
+                    Ok(())
+                })
+                .on(|ctx, Fired { .. }| {
+                    *ctx.state += 1;
+                    let count = *ctx.state;
+                    ctx.send("c1", Fired { count })
+                })
+                .on(|ctx, Quit {}| {
+                    ctx.reply(QuitOk {})?;
+                    Err(anyhow!("asked to quit"))
+                })
+                .run_with(transport)
+        });
+        let init = json!({"src": "c0", "dest": "n1", "body": {
+            "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1", "n2"],
+        }});
+        input.send(init.to_string()).unwrap();
+        let init_ok = output.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(init_ok.contains("init_ok"));
+        (input, output, node)
+    }
+
+    fn next_message(output: &Receiver<String>) -> Message<Value> {
+        let line = output.recv_timeout(Duration::from_secs(1)).unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    fn from_c1(body: Value) -> String {
+        json!({"src": "c1", "dest": "n1", "body": body}).to_string()
+    }
+
+    fn error_code(msg: &Message<Value>) -> ErrorCode {
+        msg.decode::<Error>().unwrap().body.payload.code
+    }
+
+    #[test]
+    fn routed_requests_reach_their_handlers_and_the_rest_are_refused() {
+        let (input, output, node) = start_routed();
+        input.send(from_c1(json!({"type": "frobnicate", "msg_id": 2}))).unwrap();
+        assert_eq!(error_code(&next_message(&output)), ErrorCode::NotSupported);
+        input.send(from_c1(json!({"type": "echo", "msg_id": 3}))).unwrap();
+        assert_eq!(error_code(&next_message(&output)), ErrorCode::MalformedRequest);
+
+        // A reply to nothing is dropped without an answer.
+        input.send(from_c1(json!({"type": "relay_ok", "in_reply_to": 9}))).unwrap();
+        input.send(from_c1(json!({"type": "echo", "msg_id": 4, "echo": "hi"}))).unwrap();
+        let echoed = next_message(&output);
+        assert_eq!(echoed.body.reply_to, Some(4));
+        assert_eq!(echoed.body.payload, json!({"type": "echo_ok", "echo": "hi"}));
+
+        drop(input);
+        node.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn routed_handlers_call_peers_and_schedule_messages_to_themselves() {
+        let (input, output, node) = start_routed();
+        let answer = |call: &Message<Value>, kind: &str| {
+            let body = json!({"type": kind, "echo": "hi", "in_reply_to": call.body.id});
+            json!({"src": "n2", "dest": "n1", "body": body}).to_string()
+        };
+        input.send(from_c1(json!({"type": "relay", "msg_id": 2, "echo": "hi"}))).unwrap();
+        let call = next_message(&output);
+        assert_eq!((call.dest.as_str(), call.payload_type()), ("n2", Some("echo")));
+        input.send(answer(&call, "echo_ok")).unwrap();
+        let relayed = next_message(&output);
+        assert_eq!((relayed.dest.as_str(), relayed.body.reply_to), ("c1", Some(2)));
+        assert_eq!(relayed.body.payload, json!({"type": "relay_ok", "echo": "hi"}));
+
+        // A reply of the wrong type fails the call rather than reaching the handler as data.
+        input.send(from_c1(json!({"type": "relay", "msg_id": 3, "echo": "hi"}))).unwrap();
+        let call = next_message(&output);
+        input.send(answer(&call, "relay_ok")).unwrap();
+        let failed = next_message(&output);
+        assert_eq!(failed.body.reply_to, Some(3));
+        assert_eq!(error_code(&failed), ErrorCode::Crash);
+
+        input.send(from_c1(json!({"type": "later", "msg_id": 4}))).unwrap();
+        let fired = next_message(&output);
+        assert_eq!(fired.body.payload, json!({"type": "fired", "count": 1}));
+
+        drop(input);
+        node.join().unwrap().unwrap();
+    }
+
+    /// What `reject_malformed` sends back for `line`, if anything.
+    fn rejection(line: &str) -> Option<Message<Value>> {
+        let err = serde_json::from_str::<Message<Value>>(line).unwrap_err();
+        let (tx, rx) = mpsc::channel();
+        let mut output = Output::new(ChannelWriter::new(tx));
+        let mut node = Named {
+            name: "n1".to_string(),
+            msg_id: 1,
+        };
+        reject_malformed(&mut node, line, err, &mut output).unwrap();
+        output.flush_tick().unwrap();
+        let reply = rx.try_recv().ok()?;
+        Some(serde_json::from_str(&reply).unwrap())
+    }
+
+    #[test]
+    fn malformed_json_is_answered_when_its_sender_can_be_found() {
+        let no_dest = r#"{"src": "c1", "body": {"type": "echo", "msg_id": 5}}"#;
+        let reply = rejection(no_dest).unwrap();
+        assert_eq!((reply.dest.as_str(), reply.body.reply_to), ("c1", Some(5)));
+        assert_eq!(reply.payload_type(), Some("error"));
+
+        // Not JSON at all, but the header survived.
+        let cut_short = r#"{"src": "c2", "dest": "n1", "body": {"msg_id": 7, "type": "ech"#;
+        let reply = rejection(cut_short).unwrap();
+        assert_eq!((reply.dest.as_str(), reply.body.reply_to), ("c2", Some(7)));
+        let code = reply.decode::<Error>().unwrap().body.payload.code;
+        assert_eq!(code, ErrorCode::MalformedRequest);
+
+        assert!(rejection(r#"{"src": "c1", "body": {"type": "echo"}}"#).is_none());
+        assert!(rejection(r#"{"dest": "n1", "body": {"msg_id": 7,"#).is_none());
+        assert!(rejection("garbage").is_none());
+    }
+
+    #[test]
+    fn what_a_failing_node_sent_still_goes_out() {
+        let (input, output, node) = start_routed();
+        input.send(from_c1(json!({"type": "quit", "msg_id": 2}))).unwrap();
+        let err = node.join().unwrap().unwrap_err();
+        assert!(format!("{err:#}").contains("asked to quit"));
+        assert_eq!(next_message(&output).payload_type(), Some("quit_ok"));
+    }
+
+    #[test]
+    fn bytes_per_tick_are_counted_without_metrics() {
+        let (tx, rx) = mpsc::channel();
+        let mut output = Output::new(ChannelWriter::new(tx));
+        let msg = Message::new("n1".to_string(), "c1".to_string(), Body::new(None, json!({})));
+        msg.send(&mut output).unwrap();
+        msg.send(&mut output).unwrap();
+        output.flush_tick().unwrap();
+        let lines: Vec<String> = rx.try_iter().collect();
+        let written: usize = lines.iter().map(|line| line.len() + 1).sum();
+        assert_eq!((lines.len(), output.last_tick_bytes()), (2, written));
+
+        // A turn that sent nothing leaves the count of the last one that did.
+        output.flush_tick().unwrap();
+        assert_eq!(output.last_tick_bytes(), written);
+    }
+
+    #[test]
+    fn undecodable_requests_are_told_why() {
+        let mut sim: Simulation<Broadcaster, Payload> =
+            Simulation::new(1, SimConfig::default()).unwrap();
+        sim.send("c1", "n1", json!({"type": "echo"})).unwrap();
+        sim.send("c1", "n1", json!({"type": "broadcast", "message": "seven"})).unwrap();
+        sim.run_for(Duration::from_millis(100)).unwrap();
+
+        let codes: Vec<ErrorCode> = sim
+            .take_client_messages()
+            .into_iter()
+            .map(|msg| msg.decode::<Error>().unwrap().body.payload.code)
+            .collect();
+        assert_eq!(codes, [ErrorCode::NotSupported, ErrorCode::MalformedRequest]);
+    }
+
+    fn init(msg_id: usize, node_ids: Option<&[&str]>) -> String {
+        let mut body = json!({"type": "init", "msg_id": msg_id, "node_id": "n1"});
+        if let Some(node_ids) = node_ids {
+            body["node_ids"] = json!(node_ids);
+        }
+        json!({"src": "c0", "dest": "n1", "body": body}).to_string()
+    }
+
+    #[test]
+    fn requests_before_init_are_refused_until_it_arrives() {
+        let (transport, input, output) = Channel::pair();
+        let node = thread::spawn(move || main_loop_with::<Ticker, Value, (), _>(transport));
+
+        input.send("garbage".to_string()).unwrap();
+        input.send(from_c1(json!({"type": "read", "msg_id": 2}))).unwrap();
+        let refused = next_message(&output);
+        assert_eq!(refused.body.reply_to, Some(2));
+        assert_eq!(error_code(&refused), ErrorCode::TemporarilyUnavailable);
+
+        // Replies and messages without a msg_id have nobody to answer, so they go unanswered.
+        input.send(from_c1(json!({"type": "read_ok", "in_reply_to": 1}))).unwrap();
+        input.send(from_c1(json!({"type": "read"}))).unwrap();
+        input.send(init(3, None)).unwrap();
+        let refused = next_message(&output);
+        assert_eq!(refused.body.reply_to, Some(3));
+        assert_eq!(error_code(&refused), ErrorCode::MalformedRequest);
+
+        input.send(init(4, Some(&["n1"]))).unwrap();
+        let init_ok = next_message(&output);
+        assert_eq!(init_ok.payload_type(), Some("init_ok"));
+        assert_eq!(init_ok.body.reply_to, Some(4));
+        input.send(request("every")).unwrap();
+        assert_eq!(next_message(&output).payload_type(), Some("tick"));
+
+        drop(input);
+        node.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn a_repeated_init_is_acknowledged_again() {
+        let (input, output, node) = start_ticker();
+        input.send(init(9, Some(&["n1"]))).unwrap();
+        let init_ok = next_message(&output);
+        assert_eq!(init_ok.src, "n1");
+        assert_eq!(init_ok.payload_type(), Some("init_ok"));
+        assert_eq!(init_ok.body.reply_to, Some(9));
+
+        // The node carries on as it was rather than starting over.
+        input.send(request("every")).unwrap();
+        assert_eq!(next_message(&output).payload_type(), Some("tick"));
+
+        drop(input);
+        node.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn empty_input_ends_the_loop_cleanly() {
+        let (transport, input, output) = Channel::pair();
+        drop(input);
+        main_loop_with::<Ticker, Value, (), _>(transport).unwrap();
+        assert!(output.try_recv().is_err());
+    }
+}
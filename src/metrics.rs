@@ -0,0 +1,215 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use serde_json::{json, Value};
+
+/// Set to anything but `0` or `false` to have `main_loop` collect metrics and write a summary
+/// of them to stderr when its input ends.
+pub const METRICS_ENV: &str = "MAELSTROM_METRICS";
+
+/// A histogram over power-of-two buckets: cheap to record into, and precise to within a
+/// factor of two, which is plenty for spotting a slow tail.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    buckets: [u64; 65],
+    count: u64,
+    sum: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; 65],
+            count: 0,
+            sum: 0,
+            max: 0,
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&mut self, value: u64) {
+        let bucket = (u64::BITS - value.leading_zeros()) as usize;
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+        self.max = self.max.max(value);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.sum as f64 / self.count as f64
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// An upper bound on the `quantile` (0.0..=1.0) of the recorded values.
+    pub fn quantile(&self, quantile: f64) -> u64 {
+        let rank = (self.count as f64 * quantile.clamp(0.0, 1.0))
+            .ceil()
+            .max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let upper = if bucket == 0 {
+                    0
+                } else {
+                    (1u128 << bucket) - 1
+                };
+                return (upper as u64).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    fn summary(&self) -> Value {
+        json!({
+            "count": self.count,
+            "mean": self.mean(),
+            "p50": self.quantile(0.5),
+            "p90": self.quantile(0.9),
+            "p99": self.quantile(0.99),
+            "max": self.max,
+        })
+    }
+}
+
+/// Traffic counts for one direction.
+#[derive(Debug, Clone, Default)]
+pub struct Traffic {
+    pub by_type: BTreeMap<String, u64>,
+    pub by_peer: BTreeMap<String, u64>,
+}
+
+impl Traffic {
+    fn record(&mut self, msg: &Value, peer_field: &str) {
+        let kind = msg.pointer("/body/type").and_then(Value::as_str);
+        let peer = msg.get(peer_field).and_then(Value::as_str);
+        *self
+            .by_type
+            .entry(kind.unwrap_or("?").to_string())
+            .or_default() += 1;
+        *self
+            .by_peer
+            .entry(peer.unwrap_or("?").to_string())
+            .or_default() += 1;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.by_type.values().sum()
+    }
+
+    fn summary(&self) -> Value {
+        json!({
+            "total": self.total(),
+            "by_type": self.by_type,
+            "by_peer": self.by_peer,
+        })
+    }
+}
+
+/// What a node sent and received, how long its rpcs took to be answered, how many inputs
+/// were waiting each time `main_loop` picked one up, and how many bytes each of its turns
+/// wrote. Under `concurrent_main_loop`, the queue depth is instead how many messages and
+/// timer payloads were waiting for a worker each time one was queued.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    pub sent: Traffic,
+    pub received: Traffic,
+    /// Microseconds from a request's first send to its reply.
+    pub rpc_latency: Histogram,
+    pub queue_depth: Histogram,
+    /// Bytes flushed at the end of each main loop turn that wrote anything.
+    pub bytes_per_tick: Histogram,
+}
+
+impl Metrics {
+    pub(crate) fn record_sent(&mut self, msg: &Value) {
+        self.sent.record(msg, "dest");
+    }
+
+    pub(crate) fn record_received(&mut self, msg: &Value) {
+        self.received.record(msg, "src");
+    }
+
+    pub(crate) fn record_rpc_latency(&mut self, latency: Duration) {
+        self.rpc_latency.record(latency.as_micros() as u64);
+    }
+
+    pub(crate) fn record_queue_depth(&mut self, depth: usize) {
+        self.queue_depth.record(depth as u64);
+    }
+
+    pub(crate) fn record_tick_bytes(&mut self, bytes: usize) {
+        self.bytes_per_tick.record(bytes as u64);
+    }
+
+    pub fn summary(&self) -> Value {
+        json!({
+            "sent": self.sent.summary(),
+            "received": self.received.summary(),
+            "rpc_latency_us": self.rpc_latency.summary(),
+            "queue_depth": self.queue_depth.summary(),
+            "bytes_per_tick": self.bytes_per_tick.summary(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn histogram(values: impl IntoIterator<Item = u64>) -> Histogram {
+        let mut histogram = Histogram::default();
+        for value in values {
+            histogram.record(value);
+        }
+        histogram
+    }
+
+    #[test]
+    fn an_empty_histogram_reports_zero() {
+        let histogram = Histogram::default();
+        assert_eq!(histogram.quantile(0.0), 0);
+        assert_eq!(histogram.quantile(0.5), 0);
+        assert_eq!(histogram.quantile(1.0), 0);
+        assert_eq!(histogram.mean(), 0.0);
+    }
+
+    #[test]
+    fn quantiles_are_bucket_upper_bounds_capped_at_the_max() {
+        let histogram = histogram(1..=100);
+        // Rank 50 falls in the 32..=63 bucket.
+        assert_eq!(histogram.quantile(0.5), 63);
+        assert_eq!(histogram.quantile(0.9), 100);
+        assert_eq!(histogram.quantile(1.0), 100);
+        // The lowest quantile is still the first value's bucket, not zero.
+        assert_eq!(histogram.quantile(0.0), 1);
+    }
+
+    #[test]
+    fn out_of_range_quantiles_are_clamped() {
+        let histogram = histogram([0, 5, 1000]);
+        assert_eq!(histogram.quantile(-1.0), histogram.quantile(0.0));
+        assert_eq!(histogram.quantile(2.0), 1000);
+        assert_eq!(histogram.quantile(f64::NAN), 0);
+    }
+
+    #[test]
+    fn zeros_and_the_largest_values_have_buckets() {
+        assert_eq!(histogram([0, 0]).quantile(1.0), 0);
+
+        let histogram = histogram([u64::MAX, u64::MAX]);
+        assert_eq!(histogram.quantile(0.5), u64::MAX);
+        assert_eq!(histogram.max(), u64::MAX);
+    }
+}
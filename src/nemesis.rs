@@ -0,0 +1,235 @@
+use std::{collections::HashMap, ops::Range, time::Duration};
+
+use rand::{rngs::StdRng, Rng};
+
+/// How long a message spends in flight in the simulator.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Latency {
+    Fixed(Duration),
+    Uniform(Range<Duration>),
+    /// Mostly short with a long tail, capped at `max`.
+    Exponential {
+        mean: Duration,
+        max: Duration,
+    },
+}
+
+impl Latency {
+    pub(crate) fn sample(&self, rng: &mut StdRng) -> Duration {
+        match self {
+            Latency::Fixed(latency) => *latency,
+            Latency::Uniform(range) if range.is_empty() => range.start,
+            Latency::Uniform(range) => rng.random_range(range.clone()),
+            Latency::Exponential { mean, max } => {
+                let sample = -(1.0 - rng.random::<f64>()).ln();
+                mean.mul_f64(sample).min(*max)
+            }
+        }
+    }
+}
+
+impl From<Range<Duration>> for Latency {
+    fn from(range: Range<Duration>) -> Self {
+        Latency::Uniform(range)
+    }
+}
+
+/// Faults the simulator applies to messages between nodes. Client traffic is never touched,
+/// as in Maelstrom.
+///
+/// Drops, duplicates and reordering are decided per message when it is sent. Partitions are
+/// checked when a message arrives, so one that was already in flight when the network split
+/// is lost too.
+#[derive(Debug, Clone, Default)]
+pub struct Nemesis {
+    drop_rate: f64,
+    duplicate_rate: f64,
+    reorder_rate: f64,
+    reorder_delay: Duration,
+    groups: HashMap<String, usize>,
+    dropped: usize,
+    duplicated: usize,
+}
+
+impl Nemesis {
+    /// Loses `rate` (0.0..=1.0) of messages.
+    pub fn with_drops(mut self, rate: f64) -> Self {
+        self.drop_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Delivers `rate` of messages twice.
+    pub fn with_duplicates(mut self, rate: f64) -> Self {
+        self.duplicate_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Holds `rate` of messages back an extra `delay`, letting later ones overtake them.
+    pub fn with_reordering(mut self, rate: f64, delay: Duration) -> Self {
+        self.reorder_rate = rate.clamp(0.0, 1.0);
+        self.reorder_delay = delay;
+        self
+    }
+
+    /// Splits the cluster so nodes can only reach others in the same group. Nodes left out of
+    /// every group form one more group together.
+    pub fn partition(&mut self, groups: &[&[&str]]) {
+        self.groups = groups
+            .iter()
+            .enumerate()
+            .flat_map(|(group, nodes)| nodes.iter().map(move |node| (node.to_string(), group)))
+            .collect();
+    }
+
+    /// Cuts `node` off from every other node.
+    pub fn isolate(&mut self, node: &str) {
+        self.partition(&[&[node]]);
+    }
+
+    pub fn heal(&mut self) {
+        self.groups.clear();
+    }
+
+    pub fn is_partitioned(&self) -> bool {
+        !self.groups.is_empty()
+    }
+
+    /// Messages lost to drops or partitions so far.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    pub fn duplicated(&self) -> usize {
+        self.duplicated
+    }
+
+    pub(crate) fn blocks(&mut self, src: &str, dest: &str) -> bool {
+        if self.groups.is_empty() {
+            return false;
+        }
+        let group = |node: &str| self.groups.get(node).copied();
+        let blocked = group(src) != group(dest);
+        if blocked {
+            self.dropped += 1;
+        }
+        blocked
+    }
+
+    /// Extra delays for each copy of a message that should go out; none if it is dropped.
+    pub(crate) fn copies(&mut self, rng: &mut StdRng) -> Vec<Duration> {
+        if rng.random_bool(self.drop_rate) {
+            self.dropped += 1;
+            return Vec::new();
+        }
+        let copies = if rng.random_bool(self.duplicate_rate) {
+            self.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        (0..copies)
+            .map(|_| {
+                if rng.random_bool(self.reorder_rate) {
+                    self.reorder_delay
+                } else {
+                    Duration::ZERO
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::{
+        testing::{Broadcaster, Payload},
+        SimConfig, Simulation,
+    };
+
+    #[test]
+    fn partitions_block_across_groups_until_healed() {
+        let mut nemesis = Nemesis::default();
+        nemesis.partition(&[&["n1", "n2"], &["n3"]]);
+        assert!(nemesis.is_partitioned());
+        assert!(!nemesis.blocks("n1", "n2"));
+        assert!(nemesis.blocks("n1", "n3"));
+        assert!(nemesis.blocks("n3", "n2"));
+        // Nodes left out of every group are together in one more.
+        assert!(!nemesis.blocks("n4", "n5"));
+        assert!(nemesis.blocks("n4", "n1"));
+        assert_eq!(nemesis.dropped(), 3);
+
+        nemesis.heal();
+        assert!(!nemesis.is_partitioned());
+        assert!(!nemesis.blocks("n1", "n3"));
+
+        nemesis.isolate("n2");
+        assert!(nemesis.blocks("n2", "n1"));
+        assert!(!nemesis.blocks("n1", "n3"));
+    }
+
+    #[test]
+    fn drops_and_duplicates_follow_their_rates() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut nemesis = Nemesis::default().with_drops(1.0);
+        assert!(nemesis.copies(&mut rng).is_empty());
+        assert_eq!(nemesis.dropped(), 1);
+
+        let delay = Duration::from_millis(50);
+        let mut nemesis = Nemesis::default()
+            .with_duplicates(1.0)
+            .with_reordering(1.0, delay);
+        assert_eq!(nemesis.copies(&mut rng), [delay, delay]);
+        assert_eq!(nemesis.duplicated(), 1);
+
+        let mut nemesis = Nemesis::default();
+        assert_eq!(nemesis.copies(&mut rng), [Duration::ZERO]);
+        assert_eq!((nemesis.dropped(), nemesis.duplicated()), (0, 0));
+    }
+
+    fn messages(sim: &Simulation<Broadcaster, Payload>, node: &str) -> Vec<u64> {
+        let mut messages = sim.node(node).unwrap().messages.clone();
+        messages.sort();
+        messages
+    }
+
+    #[test]
+    fn broadcast_converges_once_a_partition_heals() {
+        let mut sim: Simulation<Broadcaster, Payload> =
+            Simulation::new(3, SimConfig::default()).unwrap();
+        sim.nemesis_mut().partition(&[&["n1"], &["n2", "n3"]]);
+        sim.send("c1", "n1", Payload::Broadcast { message: 1 }).unwrap();
+        sim.send("c1", "n2", Payload::Broadcast { message: 2 }).unwrap();
+        sim.run_for(Duration::from_millis(500)).unwrap();
+        assert_eq!(messages(&sim, "n1"), [1]);
+        assert_eq!(messages(&sim, "n3"), [2]);
+        assert!(sim.nemesis().dropped() > 0);
+
+        sim.nemesis_mut().heal();
+        sim.run_for(Duration::from_secs(1)).unwrap();
+        for node in ["n1", "n2", "n3"] {
+            assert_eq!(messages(&sim, node), [1, 2], "{node}");
+        }
+    }
+
+    #[test]
+    fn broadcast_converges_despite_drops() {
+        let config = SimConfig {
+            nemesis: Nemesis::default().with_drops(0.5).with_duplicates(0.2),
+            ..SimConfig::default()
+        };
+        let mut sim: Simulation<Broadcaster, Payload> = Simulation::new(3, config).unwrap();
+        for message in 0..10 {
+            let node = format!("n{}", message % 3 + 1);
+            sim.send("c1", &node, Payload::Broadcast { message }).unwrap();
+        }
+        sim.run_for(Duration::from_secs(5)).unwrap();
+        assert!(sim.nemesis().dropped() > 0);
+        for node in ["n1", "n2", "n3"] {
+            assert_eq!(messages(&sim, node), (0..10).collect::<Vec<_>>(), "{node}");
+        }
+    }
+}
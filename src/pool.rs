@@ -0,0 +1,426 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io::Write,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{
+    init_ok, reject_malformed, reject_payload, start, Body, Error, Identity, Init, Message, Output,
+    Stdio, TimerId, TimerWheel, Transport,
+};
+
+/// A node whose messages are handled on a pool of worker threads by `concurrent_main_loop`,
+/// so a slow handler only holds up its own worker instead of every message behind it.
+///
+/// Handlers share the node by reference, so it keeps its state behind its own locks: one
+/// `Mutex` for the lot, or a `Sharded` map when handlers mostly touch different keys.
+///
+/// Timers scheduled through the `Outbox` fire on the main loop's thread, which hands their
+/// payloads to `handle_injected` on the next free worker like any message. There is no `Rpc`
+/// table: replies to the node's own requests come to `handle` as well.
+pub trait ConcurrentNode<Payload, InjectedPayload = ()>: Send + Sync + 'static {
+    fn from_init(init: Init, outbox: &Outbox<InjectedPayload>) -> Result<Self>
+    where
+        Self: Sized;
+    fn handle(&self, msg: Message<Payload>, outbox: &Outbox<InjectedPayload>) -> Result<()>;
+    fn handle_injected(
+        &self,
+        _payload: InjectedPayload,
+        _outbox: &Outbox<InjectedPayload>,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+enum PoolInput {
+    Line(String),
+    /// A complete line, newline included, for the writer.
+    Send(String),
+    TimersChanged,
+    Failed(anyhow::Error),
+    Eof,
+}
+
+enum Job<Payload, InjectedPayload> {
+    Message(Message<Payload>),
+    Injected(InjectedPayload),
+}
+
+/// How a `ConcurrentNode` sends messages and schedules timers from any thread. Messages are
+/// serialized by the sender and written whole by the main loop's thread, so lines never
+/// interleave.
+pub struct Outbox<InjectedPayload = ()> {
+    tx: Sender<PoolInput>,
+    timers: Arc<Mutex<TimerWheel<InjectedPayload>>>,
+    node_id: Arc<str>,
+    node_ids: Arc<[String]>,
+    msg_id: Arc<AtomicUsize>,
+}
+
+impl<InjectedPayload> Clone for Outbox<InjectedPayload> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            timers: self.timers.clone(),
+            node_id: self.node_id.clone(),
+            node_ids: self.node_ids.clone(),
+            msg_id: self.msg_id.clone(),
+        }
+    }
+}
+
+impl<InjectedPayload> Outbox<InjectedPayload> {
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub fn node_ids(&self) -> &[String] {
+        &self.node_ids
+    }
+
+    pub fn next_msg_id(&self) -> usize {
+        self.msg_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn send(&self, dest: String, payload: impl Serialize) -> Result<()> {
+        self.write(Message::new(
+            self.node_id.to_string(),
+            dest,
+            Body::new(Some(self.next_msg_id()), payload),
+        ))
+    }
+
+    pub fn reply<Req>(&self, msg: &Message<Req>, payload: impl Serialize) -> Result<()> {
+        let body = Body {
+            id: Some(self.next_msg_id()),
+            reply_to: msg.body.id,
+            payload,
+        };
+        self.write(Message::new(
+            self.node_id.to_string(),
+            msg.src.clone(),
+            body,
+        ))
+    }
+
+    pub fn reply_error<Req>(&self, msg: &Message<Req>, error: Error) -> Result<()> {
+        self.reply(msg, error)
+    }
+
+    /// Delivers `payload` to `handle_injected` once, `after` from now.
+    pub fn schedule_once(&self, after: Duration, payload: InjectedPayload) -> TimerId {
+        let id = self.lock_timers().schedule_once(after, payload);
+        self.wake();
+        id
+    }
+
+    /// Delivers a clone of `payload` to `handle_injected` every `period` until cancelled or
+    /// the input ends.
+    pub fn schedule_every(&self, period: Duration, payload: InjectedPayload) -> TimerId
+    where
+        InjectedPayload: Clone,
+    {
+        let id = self.lock_timers().schedule_every(period, payload);
+        self.wake();
+        id
+    }
+
+    pub fn cancel(&self, id: TimerId) -> bool {
+        self.lock_timers().cancel(id)
+    }
+
+    fn lock_timers(&self) -> MutexGuard<'_, TimerWheel<InjectedPayload>> {
+        self.timers.lock().expect("timer lock poisoned")
+    }
+
+    fn wake(&self) {
+        let _ = self.tx.send(PoolInput::TimersChanged);
+    }
+
+    fn write(&self, msg: Message<impl Serialize>) -> Result<()> {
+        let mut line = Vec::new();
+        msg.send(&mut line)?;
+        let line = String::from_utf8(line).context("message is not utf-8")?;
+        self.tx
+            .send(PoolInput::Send(line))
+            .map_err(|_| anyhow!("main loop is no longer running"))
+    }
+}
+
+impl<InjectedPayload> Identity for Outbox<InjectedPayload> {
+    fn next_msg_id(&mut self) -> usize {
+        Outbox::next_msg_id(self)
+    }
+
+    fn node_id(&self) -> String {
+        self.node_id.to_string()
+    }
+}
+
+/// Runs a `ConcurrentNode` over stdio with `workers` threads handling its messages.
+pub fn concurrent_main_loop<N, Payload, InjectedPayload>(workers: usize) -> Result<()>
+where
+    N: ConcurrentNode<Payload, InjectedPayload>,
+    Payload: DeserializeOwned + Send + 'static,
+    InjectedPayload: Send + 'static,
+{
+    concurrent_main_loop_with::<N, Payload, InjectedPayload, _>(workers, Stdio)
+}
+
+/// Like `concurrent_main_loop`, reading and writing through `transport` instead of stdio.
+///
+/// The main thread parses each input line and hands it to the next free worker, and is the
+/// only one writing output. It also fires the node's timers, queueing their payloads for the
+/// workers behind whatever messages are already waiting. Once the input ends, timers stop,
+/// workers finish what they were given, the messages they sent are written, and the loop
+/// returns. The first handler error stops it.
+pub fn concurrent_main_loop_with<N, Payload, InjectedPayload, T>(
+    workers: usize,
+    transport: T,
+) -> Result<()>
+where
+    N: ConcurrentNode<Payload, InjectedPayload>,
+    Payload: DeserializeOwned + Send + 'static,
+    InjectedPayload: Send + 'static,
+    T: Transport,
+{
+    let Some((lines, mut output, init)) = start(transport)? else {
+        return Ok(());
+    };
+    let (tx, rx) = mpsc::channel();
+    let timers = Arc::new(Mutex::new(TimerWheel::new(Instant::now())));
+    let mut outbox = Outbox {
+        tx: tx.clone(),
+        timers: timers.clone(),
+        node_id: init.node_id.as_str().into(),
+        node_ids: init.node_ids.as_slice().into(),
+        msg_id: Arc::new(AtomicUsize::new(1)),
+    };
+    let node = Arc::new(N::from_init(init, &outbox).context("node init failed")?);
+
+    let (jobs, job_rx) = mpsc::channel();
+    let job_rx = Arc::new(Mutex::new(job_rx));
+    let backlog = Arc::new(AtomicUsize::new(0));
+    let handles: Vec<_> = (0..workers.max(1))
+        .map(|_| {
+            let (node, outbox) = (node.clone(), outbox.clone());
+            let (job_rx, backlog) = (job_rx.clone(), backlog.clone());
+            thread::spawn(move || work(&*node, &outbox, &job_rx, &backlog))
+        })
+        .collect();
+
+    thread::spawn(move || {
+        for line in lines {
+            let input = match line {
+                Ok(line) => PoolInput::Line(line),
+                Err(err) => PoolInput::Failed(anyhow::Error::new(err).context("read input")),
+            };
+            if tx.send(input).is_err() {
+                return;
+            }
+        }
+        let _ = tx.send(PoolInput::Eof);
+    });
+
+    loop {
+        output.flush_tick()?;
+        let wakeup = timers.lock().expect("timer lock poisoned").next_wakeup();
+        let timeout = wakeup.map(|wakeup| wakeup.saturating_duration_since(Instant::now()));
+        let input = match timeout {
+            Some(timeout) => match rx.recv_timeout(timeout) {
+                Ok(input) => Some(input),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            },
+            None => match rx.recv() {
+                Ok(input) => Some(input),
+                Err(_) => break,
+            },
+        };
+        let fired = timers.lock().expect("timer lock poisoned").fire(Instant::now());
+        for payload in fired {
+            output.trace_injected(true);
+            queue(&jobs, &backlog, &mut output, Job::Injected(payload));
+        }
+        let Some(input) = input else {
+            continue;
+        };
+        match input {
+            PoolInput::Line(line) => {
+                if let Some(msg) = dispatch::<Payload, _>(&line, &mut outbox, &mut output)? {
+                    queue(&jobs, &backlog, &mut output, Job::Message(msg));
+                }
+            }
+            PoolInput::Send(line) => output.write_all(line.as_bytes())?,
+            PoolInput::TimersChanged => {}
+            PoolInput::Failed(err) => return Err(err),
+            PoolInput::Eof => break,
+        }
+    }
+
+    timers.lock().expect("timer lock poisoned").clear();
+    drop(jobs);
+    for handle in handles {
+        handle.join().expect("worker thread panicked");
+    }
+    for input in rx.try_iter() {
+        match input {
+            PoolInput::Send(line) => output.write_all(line.as_bytes())?,
+            PoolInput::Failed(err) => return Err(err),
+            PoolInput::Line(_) | PoolInput::TimersChanged | PoolInput::Eof => {}
+        }
+    }
+    output.flush_tick()?;
+    output.dump_metrics(outbox.node_id());
+    Ok(())
+}
+
+fn queue<T>(jobs: &Sender<T>, backlog: &AtomicUsize, output: &mut Output, job: T) {
+    output.record_queue_depth(backlog.fetch_add(1, Ordering::Relaxed));
+    // Workers only stop once `jobs` is dropped, so this can't fail.
+    let _ = jobs.send(job);
+}
+
+fn work<N, Payload, InjectedPayload>(
+    node: &N,
+    outbox: &Outbox<InjectedPayload>,
+    jobs: &Mutex<Receiver<Job<Payload, InjectedPayload>>>,
+    backlog: &AtomicUsize,
+) where
+    N: ConcurrentNode<Payload, InjectedPayload>,
+{
+    loop {
+        // The queue stays locked only until a job arrives, never while it is handled.
+        let job = jobs.lock().expect("job queue lock poisoned").recv();
+        let Ok(job) = job else {
+            return;
+        };
+        backlog.fetch_sub(1, Ordering::Relaxed);
+        let handled = match job {
+            Job::Message(msg) => node.handle(msg, outbox),
+            Job::Injected(payload) => node.handle_injected(payload, outbox),
+        };
+        if let Err(err) = handled {
+            let _ = outbox
+                .tx
+                .send(PoolInput::Failed(err.context("process message failed")));
+        }
+    }
+}
+
+/// Turns an input line into a message for the workers, answering anything they shouldn't see.
+fn dispatch<Payload: DeserializeOwned, InjectedPayload>(
+    line: &str,
+    outbox: &mut Outbox<InjectedPayload>,
+    output: &mut Output,
+) -> Result<Option<Message<Payload>>> {
+    output.observe_recv(line);
+    let msg: Message<Value> = match serde_json::from_str(line) {
+        Ok(msg) => msg,
+        Err(err) => return reject_malformed(outbox, line, err, output).map(|_| None),
+    };
+    if msg.payload_type() == Some("init") {
+        let id = outbox.next_msg_id();
+        init_ok(outbox.node_id().to_string(), &msg, id).send(output)?;
+        return Ok(None);
+    }
+    match msg.decode() {
+        Ok(msg) => Ok(Some(msg)),
+        Err(err) => reject_payload::<_, Payload>(outbox, msg, err, output).map(|_| None),
+    }
+}
+
+/// State split into independently locked shards by key, so handlers working on different
+/// keys rarely wait on each other.
+pub struct Sharded<T> {
+    shards: Vec<Mutex<T>>,
+}
+
+impl<T> Sharded<T> {
+    pub fn new(shards: usize, make: impl FnMut() -> T) -> Self {
+        Self {
+            shards: std::iter::repeat_with(make)
+                .map(Mutex::new)
+                .take(shards.max(1))
+                .collect(),
+        }
+    }
+
+    /// Locks the shard `key` belongs to.
+    pub fn shard(&self, key: &impl Hash) -> MutexGuard<'_, T> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let index = hasher.finish() as usize % self.shards.len();
+        self.shards[index].lock().expect("shard lock poisoned")
+    }
+
+    /// Locks every shard, always in the same order, for work spanning all keys.
+    pub fn lock_all(&self) -> Vec<MutexGuard<'_, T>> {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().expect("shard lock poisoned"))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::Channel;
+
+    /// Answers every request with a `tick` to its sender, 20ms later.
+    struct Delayed;
+
+    impl ConcurrentNode<Value, String> for Delayed {
+        fn from_init(_init: Init, _outbox: &Outbox<String>) -> Result<Self> {
+            Ok(Delayed)
+        }
+
+        fn handle(&self, msg: Message<Value>, outbox: &Outbox<String>) -> Result<()> {
+            outbox.schedule_once(Duration::from_millis(20), msg.src);
+            Ok(())
+        }
+
+        fn handle_injected(&self, dest: String, outbox: &Outbox<String>) -> Result<()> {
+            outbox.send(dest, json!({"type": "tick"}))
+        }
+    }
+
+    #[test]
+    fn timers_fire_on_the_workers() {
+        let (transport, input, output) = Channel::pair();
+        let pool = thread::spawn(move || {
+            concurrent_main_loop_with::<Delayed, Value, String, _>(2, transport)
+        });
+        let init = json!({"src": "c0", "dest": "n1", "body": {
+            "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"],
+        }});
+        input.send(init.to_string()).unwrap();
+        let start = json!({"src": "c1", "dest": "n1", "body": {"type": "start", "msg_id": 2}});
+        input.send(start.to_string()).unwrap();
+
+        let recv = || -> Message<Value> {
+            let line = output.recv_timeout(Duration::from_secs(1)).unwrap();
+            serde_json::from_str(&line).unwrap()
+        };
+        assert_eq!(recv().payload_type(), Some("init_ok"));
+        let tick = recv();
+        assert_eq!((tick.dest.as_str(), tick.payload_type()), ("c1", Some("tick")));
+
+        drop(input);
+        pool.join().unwrap().unwrap();
+    }
+}
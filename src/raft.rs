@@ -0,0 +1,660 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+/// Upper bound on entries per `AppendEntries`, so a lagging follower catches up in chunks.
+const MAX_ENTRIES_PER_APPEND: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogEntry<Command> {
+    pub term: u64,
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RequestVote {
+    pub term: u64,
+    pub candidate_id: String,
+    pub last_log_index: u64,
+    pub last_log_term: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RequestVoteResult {
+    pub term: u64,
+    pub vote_granted: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AppendEntries<Command> {
+    pub term: u64,
+    pub leader_id: String,
+    pub prev_log_index: u64,
+    pub prev_log_term: u64,
+    pub entries: Vec<LogEntry<Command>>,
+    pub leader_commit: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AppendEntriesResult {
+    pub term: u64,
+    pub success: bool,
+    /// On success, the index of the last entry now known to match the leader's log. On
+    /// failure, the follower's last index, so the leader can skip straight past the gap.
+    pub last_log_index: u64,
+}
+
+/// A request Raft wants delivered to `dest`; its answer goes back through the matching
+/// `Raft::handle_*_result` call.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RaftRequest<Command> {
+    RequestVote(RequestVote),
+    AppendEntries(AppendEntries<Command>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outbound<Command> {
+    pub dest: String,
+    pub request: RaftRequest<Command>,
+}
+
+/// Returned by `Raft::propose` on a node that can't append to the log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotLeader {
+    pub leader: Option<String>,
+}
+
+impl fmt::Display for NotLeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.leader {
+            Some(leader) => write!(f, "not the leader, try {leader}"),
+            None => f.write_str("not the leader, and no leader is known"),
+        }
+    }
+}
+
+impl std::error::Error for NotLeader {}
+
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    pub election_timeout: Range<Duration>,
+    pub heartbeat_interval: Duration,
+    /// Seeds the election timeout jitter; `None` draws a seed from the OS.
+    pub seed: Option<u64>,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Self {
+            election_timeout: Duration::from_millis(300)..Duration::from_millis(600),
+            heartbeat_interval: Duration::from_millis(50),
+            seed: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate {
+        votes: HashSet<String>,
+    },
+    Leader {
+        next_index: HashMap<String, u64>,
+        match_index: HashMap<String, u64>,
+        next_heartbeat: Instant,
+    },
+}
+
+/// Leader election and log replication for one Raft peer.
+///
+/// `Raft` does no I/O of its own: the node feeds it time through `tick`, client commands
+/// through `propose` and peer traffic through the `handle_*` methods, sends whatever
+/// `Outbound` requests come back, and applies the entries `take_committed` yields in order.
+pub struct Raft<Command> {
+    id: String,
+    peers: Vec<String>,
+    config: RaftConfig,
+    rng: StdRng,
+    current_term: u64,
+    voted_for: Option<String>,
+    log: Vec<LogEntry<Command>>,
+    commit_index: u64,
+    last_applied: u64,
+    role: Role,
+    leader: Option<String>,
+    election_deadline: Instant,
+}
+
+impl<Command: Clone> Raft<Command> {
+    pub fn new(id: String, node_ids: Vec<String>, config: RaftConfig, now: Instant) -> Self {
+        let mut peers = node_ids;
+        peers.retain(|node| node.ne(&id));
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        };
+        let mut raft = Self {
+            id,
+            peers,
+            config,
+            rng,
+            current_term: 0,
+            voted_for: None,
+            log: Vec::new(),
+            commit_index: 0,
+            last_applied: 0,
+            role: Role::Follower,
+            leader: None,
+            election_deadline: now,
+        };
+        raft.reset_election_deadline(now);
+        raft
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn term(&self) -> u64 {
+        self.current_term
+    }
+
+    pub fn is_leader(&self) -> bool {
+        matches!(self.role, Role::Leader { .. })
+    }
+
+    /// The leader this node last heard from, or itself when it leads.
+    pub fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    pub fn last_log_index(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    /// The term of the entry at `index`, or `None` if the log doesn't reach that far.
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        match index {
+            0 => Some(0),
+            index => self.log.get(index as usize - 1).map(|entry| entry.term),
+        }
+    }
+
+    fn last_log_term(&self) -> u64 {
+        self.log.last().map_or(0, |entry| entry.term)
+    }
+
+    fn majority(&self) -> usize {
+        let cluster = self.peers.len() + 1;
+        cluster / 2 + 1
+    }
+
+    fn reset_election_deadline(&mut self, now: Instant) {
+        let timeout = self.rng.random_range(self.config.election_timeout.clone());
+        self.election_deadline = now + timeout;
+    }
+
+    fn step_down(&mut self, term: u64, now: Instant) {
+        if term > self.current_term {
+            self.current_term = term;
+            self.voted_for = None;
+            // Whoever led the old term doesn't lead this one.
+            self.leader = None;
+        }
+        if !matches!(self.role, Role::Follower) {
+            self.role = Role::Follower;
+            self.reset_election_deadline(now);
+        }
+    }
+
+    /// Appends `command` to the log if this node leads, returning the index it will commit at.
+    pub fn propose(&mut self, command: Command) -> Result<u64, NotLeader> {
+        if !self.is_leader() {
+            return Err(NotLeader {
+                leader: self.leader.clone(),
+            });
+        }
+        self.log.push(LogEntry {
+            term: self.current_term,
+            command,
+        });
+        self.advance_commit_index();
+        Ok(self.last_log_index())
+    }
+
+    /// Starts an election or sends heartbeats when they are due.
+    pub fn tick(&mut self, now: Instant) -> Vec<Outbound<Command>> {
+        if let Role::Leader { next_heartbeat, .. } = &mut self.role {
+            if *next_heartbeat > now {
+                return Vec::new();
+            }
+            *next_heartbeat = now + self.config.heartbeat_interval;
+            return self.replicate();
+        }
+        if self.election_deadline > now {
+            return Vec::new();
+        }
+        self.start_election(now)
+    }
+
+    fn start_election(&mut self, now: Instant) -> Vec<Outbound<Command>> {
+        self.current_term += 1;
+        self.voted_for = Some(self.id.clone());
+        self.leader = None;
+        self.role = Role::Candidate {
+            votes: HashSet::from([self.id.clone()]),
+        };
+        self.reset_election_deadline(now);
+        if self.majority() == 1 {
+            return self.become_leader(now);
+        }
+
+        let request = RequestVote {
+            term: self.current_term,
+            candidate_id: self.id.clone(),
+            last_log_index: self.last_log_index(),
+            last_log_term: self.last_log_term(),
+        };
+        self.peers
+            .iter()
+            .map(|peer| Outbound {
+                dest: peer.clone(),
+                request: RaftRequest::RequestVote(request.clone()),
+            })
+            .collect()
+    }
+
+    fn become_leader(&mut self, now: Instant) -> Vec<Outbound<Command>> {
+        let next = self.last_log_index() + 1;
+        self.role = Role::Leader {
+            next_index: self.peers.iter().map(|peer| (peer.clone(), next)).collect(),
+            match_index: self.peers.iter().map(|peer| (peer.clone(), 0)).collect(),
+            next_heartbeat: now + self.config.heartbeat_interval,
+        };
+        self.leader = Some(self.id.clone());
+        self.advance_commit_index();
+        self.replicate()
+    }
+
+    fn replicate(&self) -> Vec<Outbound<Command>> {
+        let Role::Leader { next_index, .. } = &self.role else {
+            return Vec::new();
+        };
+        self.peers
+            .iter()
+            .map(|peer| {
+                let next = next_index[peer];
+                let prev_log_index = next - 1;
+                Outbound {
+                    dest: peer.clone(),
+                    request: RaftRequest::AppendEntries(AppendEntries {
+                        term: self.current_term,
+                        leader_id: self.id.clone(),
+                        prev_log_index,
+                        prev_log_term: self.term_at(prev_log_index).unwrap_or_default(),
+                        entries: self.log[prev_log_index as usize..]
+                            .iter()
+                            .take(MAX_ENTRIES_PER_APPEND)
+                            .cloned()
+                            .collect(),
+                        leader_commit: self.commit_index,
+                    }),
+                }
+            })
+            .collect()
+    }
+
+    /// Commits the highest index from the current term that a majority has stored.
+    fn advance_commit_index(&mut self) {
+        let Role::Leader { match_index, .. } = &self.role else {
+            return;
+        };
+        let mut indices: Vec<u64> = match_index.values().copied().collect();
+        indices.push(self.last_log_index());
+        indices.sort_unstable_by(|a, b| b.cmp(a));
+        let replicated = indices[self.majority() - 1];
+        if replicated > self.commit_index && self.term_at(replicated) == Some(self.current_term) {
+            self.commit_index = replicated;
+        }
+    }
+
+    pub fn handle_request_vote(&mut self, request: RequestVote, now: Instant) -> RequestVoteResult {
+        if request.term > self.current_term {
+            self.step_down(request.term, now);
+        }
+        let up_to_date = (request.last_log_term, request.last_log_index)
+            >= (self.last_log_term(), self.last_log_index());
+        let vote_granted = request.term == self.current_term
+            && up_to_date
+            && self
+                .voted_for
+                .as_ref()
+                .is_none_or(|voted_for| voted_for.eq(&request.candidate_id));
+        if vote_granted {
+            self.voted_for = Some(request.candidate_id);
+            self.reset_election_deadline(now);
+        }
+        RequestVoteResult {
+            term: self.current_term,
+            vote_granted,
+        }
+    }
+
+    pub fn handle_request_vote_result(
+        &mut self,
+        from: String,
+        result: RequestVoteResult,
+        now: Instant,
+    ) -> Vec<Outbound<Command>> {
+        if result.term > self.current_term {
+            self.step_down(result.term, now);
+            return Vec::new();
+        }
+        let majority = self.majority();
+        let Role::Candidate { votes } = &mut self.role else {
+            return Vec::new();
+        };
+        if result.term != self.current_term || !result.vote_granted {
+            return Vec::new();
+        }
+        votes.insert(from);
+        if votes.len() < majority {
+            return Vec::new();
+        }
+        self.become_leader(now)
+    }
+
+    pub fn handle_append_entries(
+        &mut self,
+        request: AppendEntries<Command>,
+        now: Instant,
+    ) -> AppendEntriesResult {
+        if request.term < self.current_term {
+            return AppendEntriesResult {
+                term: self.current_term,
+                success: false,
+                last_log_index: self.last_log_index(),
+            };
+        }
+        self.step_down(request.term, now);
+        self.leader = Some(request.leader_id);
+        self.reset_election_deadline(now);
+
+        if self.term_at(request.prev_log_index) != Some(request.prev_log_term) {
+            return AppendEntriesResult {
+                term: self.current_term,
+                success: false,
+                last_log_index: self
+                    .last_log_index()
+                    .min(request.prev_log_index.saturating_sub(1)),
+            };
+        }
+
+        let mut index = request.prev_log_index;
+        for entry in request.entries {
+            index += 1;
+            match self.term_at(index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => self.log.truncate(index as usize - 1),
+                None => {}
+            }
+            self.log.push(entry);
+        }
+        if request.leader_commit > self.commit_index {
+            self.commit_index = request.leader_commit.min(index);
+        }
+        AppendEntriesResult {
+            term: self.current_term,
+            success: true,
+            last_log_index: index,
+        }
+    }
+
+    pub fn handle_append_entries_result(
+        &mut self,
+        from: String,
+        result: AppendEntriesResult,
+        now: Instant,
+    ) {
+        if result.term > self.current_term {
+            self.step_down(result.term, now);
+            return;
+        }
+        if result.term != self.current_term {
+            return;
+        }
+        let Role::Leader {
+            next_index,
+            match_index,
+            ..
+        } = &mut self.role
+        else {
+            return;
+        };
+        let (Some(next), Some(matched)) = (next_index.get_mut(&from), match_index.get_mut(&from))
+        else {
+            return;
+        };
+        if result.success {
+            *matched = (*matched).max(result.last_log_index);
+            *next = *matched + 1;
+            self.advance_commit_index();
+        } else {
+            *next = (*next - 1).min(result.last_log_index + 1).max(1);
+        }
+    }
+
+    /// Entries committed since the last call, paired with their log index, for the node to
+    /// apply to its state machine in order.
+    pub fn take_committed(&mut self) -> Vec<(u64, LogEntry<Command>)> {
+        let from = self.last_applied;
+        self.last_applied = self.commit_index;
+        (from + 1..=self.commit_index)
+            .map(|index| (index, self.log[index as usize - 1].clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn cluster(start: Instant) -> Vec<Raft<u32>> {
+        let ids: Vec<String> = (1..=3).map(|n| format!("n{n}")).collect();
+        ids.iter()
+            .zip(0..)
+            .map(|(id, seed)| {
+                let config = RaftConfig {
+                    seed: Some(seed),
+                    ..RaftConfig::default()
+                };
+                Raft::new(id.clone(), ids.clone(), config, start)
+            })
+            .collect()
+    }
+
+    fn index_of(nodes: &[Raft<u32>], id: &str) -> usize {
+        nodes.iter().position(|node| node.id() == id).unwrap()
+    }
+
+    /// Delivers `outbound` from `from` and every request it leads to, until all are answered.
+    fn deliver(nodes: &mut [Raft<u32>], from: usize, outbound: Vec<Outbound<u32>>, now: Instant) {
+        let mut queue: Vec<(usize, Outbound<u32>)> =
+            outbound.into_iter().map(|out| (from, out)).collect();
+        while let Some((src, Outbound { dest, request })) = queue.pop() {
+            let dest = index_of(nodes, &dest);
+            let dest_id = nodes[dest].id().to_string();
+            match request {
+                RaftRequest::RequestVote(request) => {
+                    let result = nodes[dest].handle_request_vote(request, now);
+                    let more = nodes[src].handle_request_vote_result(dest_id, result, now);
+                    queue.extend(more.into_iter().map(|out| (src, out)));
+                }
+                RaftRequest::AppendEntries(request) => {
+                    let result = nodes[dest].handle_append_entries(request, now);
+                    nodes[src].handle_append_entries_result(dest_id, result, now);
+                }
+            }
+        }
+    }
+
+    fn append(
+        term: u64,
+        leader: &str,
+        prev: (u64, u64),
+        entries: &[(u64, u32)],
+    ) -> AppendEntries<u32> {
+        AppendEntries {
+            term,
+            leader_id: leader.to_string(),
+            prev_log_index: prev.0,
+            prev_log_term: prev.1,
+            entries: entries
+                .iter()
+                .map(|&(term, command)| LogEntry { term, command })
+                .collect(),
+            leader_commit: 0,
+        }
+    }
+
+    #[test]
+    fn a_timed_out_follower_wins_the_election() {
+        let start = Instant::now();
+        let mut nodes = cluster(start);
+        let now = start + 600 * MS;
+        let outbound = nodes[0].tick(now);
+        assert_eq!(outbound.len(), 2);
+        deliver(&mut nodes, 0, outbound, now);
+
+        assert!(nodes[0].is_leader());
+        // The winner announces itself right away, so followers redirect to it.
+        for node in &nodes {
+            assert_eq!((node.term(), node.leader()), (1, Some("n1")));
+        }
+        let leader = Some("n1".to_string());
+        assert_eq!(nodes[1].propose(7), Err(NotLeader { leader }));
+        assert_eq!(nodes[0].propose(7), Ok(1));
+    }
+
+    #[test]
+    fn a_conflicting_suffix_is_truncated() {
+        let now = Instant::now();
+        let mut follower = cluster(now).remove(1);
+        let first = append(1, "n1", (0, 0), &[(1, 10), (1, 11)]);
+        assert!(follower.handle_append_entries(first, now).success);
+        let stale = append(2, "n3", (2, 1), &[(2, 12)]);
+        assert!(follower.handle_append_entries(stale, now).success);
+        assert_eq!(follower.last_log_index(), 3);
+
+        // A leader whose log has a different entry at index 2 doesn't match at prev index 2.
+        let mismatch = append(3, "n1", (2, 3), &[]);
+        assert!(!follower.handle_append_entries(mismatch, now).success);
+
+        let repair = append(3, "n1", (1, 1), &[(3, 20)]);
+        let result = follower.handle_append_entries(repair, now);
+        assert!(result.success);
+        assert_eq!(result.last_log_index, 2);
+        assert_eq!(follower.last_log_index(), 2);
+        assert_eq!(
+            (follower.term_at(1), follower.term_at(2)),
+            (Some(1), Some(3))
+        );
+    }
+
+    #[test]
+    fn only_entries_from_the_current_term_commit_by_counting() {
+        let start = Instant::now();
+        let mut nodes = cluster(start);
+        // n1 holds an entry from term 2 that never committed.
+        let old = append(2, "n2", (0, 0), &[(2, 1)]);
+        nodes[0].handle_append_entries(old, start);
+
+        let now = start + 600 * MS;
+        let outbound = nodes[0].tick(now);
+        assert_eq!(nodes[0].term(), 3);
+        for (peer, out) in outbound.into_iter().enumerate() {
+            let RaftRequest::RequestVote(request) = out.request else {
+                unreachable!();
+            };
+            let result = nodes[peer + 1].handle_request_vote(request, now);
+            nodes[0].handle_request_vote_result(out.dest, result, now);
+        }
+        assert!(nodes[0].is_leader());
+
+        // n2 now stores the term-2 entry too, a majority, but it still isn't committed.
+        let stored = AppendEntriesResult {
+            term: 3,
+            success: true,
+            last_log_index: 1,
+        };
+        nodes[0].handle_append_entries_result("n2".to_string(), stored.clone(), now);
+        assert_eq!(nodes[0].commit_index(), 0);
+
+        // Once an entry of its own term is on a majority, both commit together.
+        assert_eq!(nodes[0].propose(2), Ok(2));
+        let stored = AppendEntriesResult {
+            last_log_index: 2,
+            ..stored
+        };
+        nodes[0].handle_append_entries_result("n2".to_string(), stored, now);
+        assert_eq!(nodes[0].commit_index(), 2);
+        let committed: Vec<u32> = nodes[0]
+            .take_committed()
+            .into_iter()
+            .map(|(_, entry)| entry.command)
+            .collect();
+        assert_eq!(committed, [1, 2]);
+    }
+
+    #[test]
+    fn a_stale_leader_steps_down() {
+        let start = Instant::now();
+        let mut nodes = cluster(start);
+        let now = start + 600 * MS;
+        let outbound = nodes[0].tick(now);
+        deliver(&mut nodes, 0, outbound, now);
+        assert!(nodes[0].is_leader());
+
+        // A candidate from a later term demotes it and leaves no leader known.
+        let request = RequestVote {
+            term: 5,
+            candidate_id: "n3".to_string(),
+            last_log_index: 0,
+            last_log_term: 0,
+        };
+        assert!(nodes[0].handle_request_vote(request, now).vote_granted);
+        assert!(!nodes[0].is_leader());
+        assert_eq!((nodes[0].term(), nodes[0].leader()), (5, None));
+        assert!(nodes[0].propose(1).is_err());
+
+        // A reply from a later term does the same to a leader.
+        let mut nodes = cluster(start);
+        let outbound = nodes[0].tick(now);
+        deliver(&mut nodes, 0, outbound, now);
+        let newer = AppendEntriesResult {
+            term: 2,
+            success: false,
+            last_log_index: 0,
+        };
+        nodes[0].handle_append_entries_result("n2".to_string(), newer, now);
+        assert!(!nodes[0].is_leader());
+        assert_eq!((nodes[0].term(), nodes[0].leader()), (2, None));
+
+        // And a heartbeat from the new leader tells it who leads now.
+        nodes[0].handle_append_entries(append(2, "n2", (0, 0), &[]), now);
+        assert_eq!(nodes[0].leader(), Some("n2"));
+    }
+}
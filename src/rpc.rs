@@ -0,0 +1,623 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::Write,
+    sync::mpsc::{self, Receiver},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{Error, ErrorCode, Message, MessageType, Node, Output};
+
+type ReplyHandler<State> = Box<
+    dyn FnOnce(&mut State, Result<Message<Value>, RpcError>, &mut Output) -> Result<()> + Send,
+>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RpcError {
+    /// The call's deadline passed before any reply arrived.
+    Timeout { attempts: usize },
+    /// Every attempt allowed by the retry policy went unanswered.
+    RetriesExhausted { attempts: usize },
+    /// The peer answered with a Maelstrom `error` message.
+    Remote(Error),
+    /// The reply arrived but didn't match the expected response type.
+    Malformed(String),
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::Timeout { attempts } => {
+                write!(f, "rpc timed out after {attempts} attempt(s)")
+            }
+            RpcError::RetriesExhausted { attempts } => {
+                write!(f, "rpc gave up after {attempts} unanswered attempt(s)")
+            }
+            RpcError::Remote(err) => write!(f, "rpc failed: {err}"),
+            RpcError::Malformed(err) => write!(f, "rpc reply could not be decoded: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/// What to tell a client whose request failed because a call of ours did.
+impl From<RpcError> for Error {
+    fn from(err: RpcError) -> Self {
+        match err {
+            RpcError::Remote(error) => error,
+            RpcError::Timeout { .. } | RpcError::RetriesExhausted { .. } => {
+                Error::new(ErrorCode::Timeout, err.to_string())
+            }
+            RpcError::Malformed(_) => Error::new(ErrorCode::Crash, err.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backoff {
+    Fixed(Duration),
+    /// Doubles from `initial` up to `max`; `jitter` (0.0..=1.0) is the fraction of each delay
+    /// that may be randomly shaved off so peers don't retry in lockstep.
+    Exponential {
+        initial: Duration,
+        max: Duration,
+        jitter: f64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub backoff: Backoff,
+    /// Total sends including the first one; `None` keeps retrying until the call's timeout.
+    pub max_attempts: Option<usize>,
+}
+
+impl RetryPolicy {
+    pub fn fixed(interval: Duration) -> Self {
+        Self {
+            backoff: Backoff::Fixed(interval),
+            max_attempts: None,
+        }
+    }
+
+    pub fn exponential(initial: Duration, max: Duration, jitter: f64) -> Self {
+        Self {
+            backoff: Backoff::Exponential {
+                initial,
+                max,
+                jitter: jitter.clamp(0.0, 1.0),
+            },
+            max_attempts: None,
+        }
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: usize) -> Self {
+        self.max_attempts = Some(max_attempts.max(1));
+        self
+    }
+
+    fn delay(&self, attempt: usize, rng: &mut StdRng) -> Duration {
+        match self.backoff {
+            Backoff::Fixed(interval) => interval,
+            Backoff::Exponential {
+                initial,
+                max,
+                jitter,
+            } => {
+                let exponent = attempt.saturating_sub(1).min(31) as u32;
+                let delay = initial.saturating_mul(1 << exponent).min(max);
+                delay.mul_f64(1.0 - jitter * rng.random::<f64>())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CallOptions {
+    pub timeout: Option<Duration>,
+    pub retry: Option<RetryPolicy>,
+}
+
+impl CallOptions {
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+struct Pending<State> {
+    handler: ReplyHandler<State>,
+    sent: Instant,
+    deadline: Option<Instant>,
+    retry: Option<Retry>,
+    attempts: usize,
+}
+
+struct Retry {
+    line: String,
+    policy: RetryPolicy,
+    next_send: Instant,
+}
+
+impl<State> Pending<State> {
+    fn wakeup(&self) -> Option<Instant> {
+        let next_send = self.retry.as_ref().map(|retry| retry.next_send);
+        match (self.deadline, next_send) {
+            (Some(deadline), Some(next_send)) => Some(deadline.min(next_send)),
+            (deadline, next_send) => deadline.or(next_send),
+        }
+    }
+}
+
+/// Outstanding requests sent by a node, keyed by the `msg_id` they went out with.
+///
+/// `main_loop` checks every inbound message's `in_reply_to` against this table before the
+/// node sees it, and hands matching replies to the handler registered for that request.
+/// Requests sent with `call_with` are also re-sent and expired from the same loop.
+pub struct Rpc<State> {
+    // Ordered so retries that fall due together go out in the same order on every run.
+    pending: BTreeMap<usize, Pending<State>>,
+    now: Instant,
+    rng: StdRng,
+}
+
+impl<State> Default for Rpc<State> {
+    fn default() -> Self {
+        Self {
+            pending: BTreeMap::new(),
+            now: Instant::now(),
+            rng: StdRng::from_os_rng(),
+        }
+    }
+}
+
+impl<State> Rpc<State> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes retry jitter repeatable, for the simulator.
+    pub(crate) fn reseed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Sends `msg` and calls `handler` with the reply once it arrives. Never times out.
+    pub fn call<Req, Resp, F>(
+        &mut self,
+        msg: &Message<Req>,
+        output: &mut impl Write,
+        handler: F,
+    ) -> Result<()>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+        F: FnOnce(&mut State, Result<Message<Resp>, RpcError>, &mut Output) -> Result<()>
+            + Send
+            + 'static,
+    {
+        self.call_with(msg, output, CallOptions::default(), handler)
+    }
+
+    /// Sends `msg`, re-sending it unchanged according to `options.retry` until a reply with a
+    /// matching `in_reply_to` arrives. `handler` gets an `RpcError` if the call gives up.
+    pub fn call_with<Req, Resp, F>(
+        &mut self,
+        msg: &Message<Req>,
+        output: &mut impl Write,
+        options: CallOptions,
+        handler: F,
+    ) -> Result<()>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+        F: FnOnce(&mut State, Result<Message<Resp>, RpcError>, &mut Output) -> Result<()>
+            + Send
+            + 'static,
+    {
+        let id = msg.body.id.context("rpc request must carry a msg_id")?;
+        let line = serde_json::to_string(msg).context("serialize rpc request")?;
+        output.write_all(line.as_bytes()).context("write rpc request")?;
+        output.write_all(b"\n").context("write trailing newline")?;
+
+        let retry = options.retry.map(|policy| Retry {
+            next_send: self.now + policy.delay(1, &mut self.rng),
+            line,
+            policy,
+        });
+        self.pending.insert(
+            id,
+            Pending {
+                handler: Box::new(move |state, reply, output| {
+                    let reply = reply.and_then(|msg| {
+                        let malformed = |err: serde_json::Error| RpcError::Malformed(err.to_string());
+                        if msg.payload_type() == Some("error") {
+                            let error = msg.decode::<Error>().map_err(malformed)?;
+                            return Err(RpcError::Remote(error.body.payload));
+                        }
+                        msg.decode().map_err(malformed)
+                    });
+                    handler(state, reply, output)
+                }),
+                sent: self.now,
+                deadline: options.timeout.map(|timeout| self.now + timeout),
+                retry,
+                attempts: 1,
+            },
+        );
+        Ok(())
+    }
+
+    /// Sends `msg` and returns a channel the reply will be delivered on.
+    pub fn call_channel<Req, Resp>(
+        &mut self,
+        msg: &Message<Req>,
+        output: &mut impl Write,
+        options: CallOptions,
+    ) -> Result<Receiver<Result<Message<Resp>, RpcError>>>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        self.call_with(msg, output, options, move |_, reply, _| {
+            let _ = tx.send(reply);
+            Ok(())
+        })?;
+        Ok(rx)
+    }
+
+    /// Sends `request` on behalf of `msg`'s sender and relays whatever comes back to them,
+    /// failures included. A reply of a type other than the one paired with the request's is
+    /// relayed as a failure too.
+    pub fn forward<Payload, InjectedPayload>(
+        &mut self,
+        request: &Message<Payload>,
+        msg: Message<Payload>,
+        output: &mut impl Write,
+        options: CallOptions,
+    ) -> Result<()>
+    where
+        State: Node<Payload, InjectedPayload>,
+        Payload: MessageType + Serialize + DeserializeOwned + Send + 'static,
+    {
+        let expected = Payload::response_type_for(request.body.payload.message_type());
+        self.call_with(request, output, options, move |node: &mut State, reply, output| {
+            let reply = reply.and_then(|reply: Message<Payload>| {
+                let kind = reply.body.payload.message_type();
+                match expected {
+                    Some(expected) if kind != expected => Err(RpcError::Malformed(format!(
+                        "expected a {expected} reply, got {kind}"
+                    ))),
+                    _ => Ok(reply),
+                }
+            });
+            match reply {
+                Ok(reply) => node.reply(msg, reply.body.payload).send(output),
+                Err(err) => node.reply_error(msg, err.into()).send(output),
+            }
+        })
+    }
+
+    /// The loop's clock as of the event being handled: the wall clock under `main_loop`, the
+    /// virtual one under `Simulation`.
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    pub fn is_pending(&self, msg_id: usize) -> bool {
+        self.pending.contains_key(&msg_id)
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Forgets about an outstanding request, so a late reply falls through to `process_message`.
+    pub fn cancel(&mut self, msg_id: usize) -> bool {
+        self.pending.remove(&msg_id).is_some()
+    }
+
+    /// The earliest instant at which a request needs to be re-sent or expired.
+    pub fn next_wakeup(&self) -> Option<Instant> {
+        self.pending.values().filter_map(Pending::wakeup).min()
+    }
+
+    /// Re-sends requests whose retry interval elapsed and removes those that gave up.
+    fn expire(
+        &mut self,
+        now: Instant,
+        output: &mut impl Write,
+    ) -> Result<Vec<(ReplyHandler<State>, RpcError)>> {
+        self.now = now;
+        let mut expired = Vec::new();
+        for (id, pending) in &mut self.pending {
+            if pending.deadline.is_some_and(|deadline| deadline <= now) {
+                expired.push((*id, RpcError::Timeout { attempts: pending.attempts }));
+                continue;
+            }
+            let Some(retry) = pending.retry.as_mut() else {
+                continue;
+            };
+            if retry.next_send > now {
+                continue;
+            }
+            if retry
+                .policy
+                .max_attempts
+                .is_some_and(|max| pending.attempts >= max)
+            {
+                expired.push((*id, RpcError::RetriesExhausted { attempts: pending.attempts }));
+                continue;
+            }
+            output.write_all(retry.line.as_bytes()).context("write rpc retry")?;
+            output.write_all(b"\n").context("write trailing newline")?;
+            pending.attempts += 1;
+            retry.next_send = now + retry.policy.delay(pending.attempts, &mut self.rng);
+        }
+        Ok(expired
+            .into_iter()
+            .filter_map(|(id, err)| Some((self.pending.remove(&id)?.handler, err)))
+            .collect())
+    }
+}
+
+/// Hands `msg` to its rpc handler if it answers an outstanding request, otherwise gives it back.
+pub(crate) fn route_reply<State, Payload, InjectedPayload>(
+    state: &mut State,
+    msg: Message<Value>,
+    output: &mut Output,
+) -> Result<Option<Message<Value>>>
+where
+    State: Node<Payload, InjectedPayload>,
+{
+    let Some(reply_to) = msg.body.reply_to else {
+        return Ok(Some(msg));
+    };
+    let Some(rpc) = state.rpc() else {
+        return Ok(Some(msg));
+    };
+    let Some(pending) = rpc.pending.remove(&reply_to) else {
+        return Ok(Some(msg));
+    };
+    output.record_rpc_latency(rpc.now.saturating_duration_since(pending.sent));
+    (pending.handler)(state, Ok(msg), output).context("rpc reply handler failed")?;
+    Ok(None)
+}
+
+/// Drives retries and timeouts of the node's outstanding requests up to `now`.
+pub(crate) fn tick<State, Payload, InjectedPayload>(
+    state: &mut State,
+    now: Instant,
+    output: &mut Output,
+) -> Result<()>
+where
+    State: Node<Payload, InjectedPayload>,
+{
+    let Some(rpc) = state.rpc() else {
+        return Ok(());
+    };
+    for (handler, err) in rpc.expire(now, &mut *output)? {
+        handler(state, Err(err), output).context("rpc give-up handler failed")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        handle_line, testing::Payload, transport::ChannelWriter, Body, Event, Identity, Init,
+        Injector,
+    };
+
+    const MS: Duration = Duration::from_millis(1);
+
+    /// Records how each of its calls ended, by the type of their reply, and whatever reached
+    /// `process_message`.
+    #[derive(Default)]
+    struct Caller {
+        msg_id: usize,
+        rpc: Rpc<Self>,
+        results: Vec<Result<&'static str, RpcError>>,
+        unrouted: Vec<Message<Payload>>,
+    }
+
+    impl Identity for Caller {
+        fn next_msg_id(&mut self) -> usize {
+            self.msg_id += 1;
+            self.msg_id
+        }
+
+        fn node_id(&self) -> String {
+            "n1".to_string()
+        }
+    }
+
+    impl Node<Payload> for Caller {
+        fn from_init(_init: Init, _tx: Injector<()>) -> Result<Self> {
+            Ok(Self::default())
+        }
+
+        fn rpc(&mut self) -> Option<&mut Rpc<Self>> {
+            Some(&mut self.rpc)
+        }
+
+        fn process_message(&mut self, event: Event<Payload>, _output: &mut Output) -> Result<()> {
+            if let Event::Message(msg) = event {
+                self.unrouted.push(msg);
+            }
+            Ok(())
+        }
+    }
+
+    /// A node whose clock reads `start`, and the lines it writes.
+    fn caller(start: Instant) -> (Caller, Output, Receiver<String>) {
+        let (tx, rx) = mpsc::channel();
+        let mut output = Output::new(ChannelWriter::new(tx));
+        let mut node = Caller::default();
+        tick(&mut node, start, &mut output).unwrap();
+        (node, output, rx)
+    }
+
+    /// Calls `n2` with a `read`, returning the request's `msg_id`.
+    fn call(node: &mut Caller, output: &mut Output, options: CallOptions) -> usize {
+        let msg = node.request("n2".to_string(), Payload::Read);
+        node.rpc
+            .call_with(&msg, output, options, |node: &mut Caller, reply, _| {
+                let reply = reply.map(|reply: Message<Payload>| reply.body.payload.message_type());
+                node.results.push(reply);
+                Ok(())
+            })
+            .unwrap();
+        msg.body.id.unwrap()
+    }
+
+    fn sent(output: &mut Output, rx: &Receiver<String>) -> Vec<Message<Value>> {
+        output.flush_tick().unwrap();
+        rx.try_iter()
+            .map(|line| serde_json::from_str(&line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn calls_time_out_at_their_deadline() {
+        let start = Instant::now();
+        let (mut node, mut output, rx) = caller(start);
+        let options = CallOptions::default().with_timeout(100 * MS);
+        call(&mut node, &mut output, options);
+        assert_eq!(node.rpc.next_wakeup(), Some(start + 100 * MS));
+
+        tick(&mut node, start + 99 * MS, &mut output).unwrap();
+        assert!(node.results.is_empty());
+        tick(&mut node, start + 100 * MS, &mut output).unwrap();
+        assert_eq!(node.results, [Err(RpcError::Timeout { attempts: 1 })]);
+        assert_eq!(node.rpc.pending(), 0);
+        assert_eq!(sent(&mut output, &rx).len(), 1);
+    }
+
+    #[test]
+    fn retries_stop_after_max_attempts() {
+        let start = Instant::now();
+        let (mut node, mut output, rx) = caller(start);
+        let retry = RetryPolicy::fixed(10 * MS).with_max_attempts(3);
+        let options = CallOptions::default().with_retry(retry);
+        let id = call(&mut node, &mut output, options);
+
+        for elapsed in [10, 20] {
+            tick(&mut node, start + elapsed * MS, &mut output).unwrap();
+            assert!(node.results.is_empty());
+        }
+        tick(&mut node, start + 30 * MS, &mut output).unwrap();
+        let exhausted = RpcError::RetriesExhausted { attempts: 3 };
+        assert_eq!(node.results, [Err(exhausted)]);
+
+        // Every attempt is the same request, msg_id included.
+        let sent = sent(&mut output, &rx);
+        assert_eq!(sent.len(), 3);
+        assert!(sent.iter().all(|msg| msg.body.id == Some(id)));
+    }
+
+    #[test]
+    fn exponential_backoff_doubles_up_to_max_within_its_jitter() {
+        let policy = RetryPolicy::exponential(10 * MS, 80 * MS, 0.5);
+        let mut rng = StdRng::seed_from_u64(7);
+        for (attempt, nominal) in [(1, 10), (2, 20), (3, 40), (4, 80), (5, 80), (40, 80)] {
+            let nominal = nominal * MS;
+            for _ in 0..100 {
+                let delay = policy.delay(attempt, &mut rng);
+                assert!((nominal / 2..=nominal).contains(&delay), "{attempt}: {delay:?}");
+            }
+        }
+
+        let steady = RetryPolicy::exponential(10 * MS, 80 * MS, 0.0);
+        assert_eq!(steady.delay(3, &mut rng), 40 * MS);
+        let clamped = RetryPolicy::exponential(10 * MS, 80 * MS, 3.0);
+        assert!(matches!(clamped.backoff, Backoff::Exponential { jitter, .. } if jitter == 1.0));
+    }
+
+    #[test]
+    fn forwarded_replies_of_the_wrong_type_become_errors() {
+        let (mut node, mut output, rx) = caller(Instant::now());
+        let client = |id| Message {
+            src: "c1".to_string(),
+            dest: "n1".to_string(),
+            body: Body::new(Some(id), Payload::Read),
+        };
+        let mut forward = |node: &mut Caller, id, answer: Payload| {
+            let request = node.request("n2".to_string(), Payload::Read);
+            let options = CallOptions::default();
+            node.rpc
+                .forward(&request, client(id), &mut output, options)
+                .unwrap();
+            let mut body = serde_json::to_value(Body::new(Some(1), answer)).unwrap();
+            body["in_reply_to"] = json!(request.body.id);
+            let reply = json!({"src": "n2", "dest": "n1", "body": body});
+            let reply = serde_json::from_value(reply).unwrap();
+            assert!(route_reply(node, reply, &mut output).unwrap().is_none());
+        };
+        forward(&mut node, 5, Payload::ReadOk { messages: vec![1] });
+        forward(&mut node, 6, Payload::BroadcastOk);
+
+        let to_client: Vec<Message<Value>> = sent(&mut output, &rx)
+            .into_iter()
+            .filter(|msg| msg.dest == "c1")
+            .collect();
+        assert_eq!(to_client.len(), 2);
+        assert_eq!(to_client[0].body.reply_to, Some(5));
+        let relayed = json!({"type": "read_ok", "messages": [1]});
+        assert_eq!(to_client[0].body.payload, relayed);
+        assert_eq!(to_client[1].body.reply_to, Some(6));
+        let error = to_client[1].decode::<Error>().unwrap().body.payload;
+        assert_eq!(error.code, ErrorCode::Crash);
+    }
+
+    /// A `read_ok` from `n2`, answering `in_reply_to` if given.
+    fn read_ok(in_reply_to: Option<usize>) -> String {
+        let body = json!({
+            "type": "read_ok", "messages": [], "msg_id": 1, "in_reply_to": in_reply_to,
+        });
+        json!({"src": "n2", "dest": "n1", "body": body}).to_string()
+    }
+
+    #[test]
+    fn replies_reach_the_handler_of_the_request_they_answer() {
+        let (mut node, mut output, _rx) = caller(Instant::now());
+        let first = call(&mut node, &mut output, CallOptions::default());
+        let second = call(&mut node, &mut output, CallOptions::default());
+
+        handle_line(&mut node, &read_ok(Some(second)), &mut output).unwrap();
+        assert_eq!(node.results, [Ok("read_ok")]);
+        assert!(node.rpc.is_pending(first) && !node.rpc.is_pending(second));
+        handle_line(&mut node, &read_ok(Some(first)), &mut output).unwrap();
+        assert_eq!(node.results, [Ok("read_ok"), Ok("read_ok")]);
+        assert!(node.unrouted.is_empty());
+    }
+
+    #[test]
+    fn replies_to_nothing_pending_fall_through_to_the_node() {
+        let (mut node, mut output, _rx) = caller(Instant::now());
+        let cancelled = call(&mut node, &mut output, CallOptions::default());
+        assert!(node.rpc.cancel(cancelled));
+
+        for in_reply_to in [Some(cancelled), Some(99), None] {
+            handle_line(&mut node, &read_ok(in_reply_to), &mut output).unwrap();
+        }
+        assert!(node.results.is_empty());
+        let fell_through: Vec<Option<usize>> =
+            node.unrouted.iter().map(|msg| msg.body.reply_to).collect();
+        assert_eq!(fell_through, [Some(cancelled), Some(99), None]);
+    }
+}
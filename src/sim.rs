@@ -0,0 +1,390 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    marker::PhantomData,
+    sync::{
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{
+    advance, handle_line, transport::ChannelWriter, Body, Event, History, Init, Injector, Input,
+    InputSender, Latency, Message, Nemesis, Node, Output, TimerWheel,
+};
+
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub latency: Latency,
+    pub nemesis: Nemesis,
+    pub seed: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            latency: Latency::Uniform(Duration::from_millis(1)..Duration::from_millis(10)),
+            nemesis: Nemesis::default(),
+            seed: 0,
+        }
+    }
+}
+
+struct Envelope {
+    src: String,
+    dest: String,
+    line: String,
+}
+
+type Service = Box<dyn FnMut(&Message<Value>) -> Value>;
+
+struct SimNode<State, InjectedPayload> {
+    state: State,
+    timers: Arc<Mutex<TimerWheel<InjectedPayload>>>,
+    injected: Receiver<Input<InjectedPayload>>,
+    output: Output,
+    sent: Receiver<String>,
+}
+
+/// Runs a cluster of `State` nodes inside one process on a virtual clock.
+///
+/// Messages between nodes are delayed by a latency drawn from a seeded rng and delivered in
+/// deadline order, and node timers and rpc retries fire when the clock reaches them, so two
+/// runs with the same seed and inputs see the same interleaving. Nodes should take the time
+/// from their timers rather than `Instant::now()` for that to hold. Messages a node sends to
+/// anyone outside the cluster are kept for the test to inspect, except requests to a service
+/// added with `add_service`, which it answers. Messages between nodes go through the
+/// configured `Nemesis`, which can be changed while the simulation runs.
+pub struct Simulation<State, Payload, InjectedPayload = ()> {
+    nodes: BTreeMap<String, SimNode<State, InjectedPayload>>,
+    services: HashMap<String, Service>,
+    in_flight: BTreeMap<(Instant, u64), Envelope>,
+    next_seq: u64,
+    start: Instant,
+    now: Instant,
+    rng: StdRng,
+    latency: Latency,
+    nemesis: Nemesis,
+    next_client_msg_id: usize,
+    client_inbox: Vec<Message<Value>>,
+    history: History,
+    _payload: PhantomData<fn(Payload)>,
+}
+
+impl<State, Payload, InjectedPayload> Simulation<State, Payload, InjectedPayload>
+where
+    State: Node<Payload, InjectedPayload>,
+    Payload: DeserializeOwned,
+{
+    /// Starts nodes `n1` to `n{node_count}`, as if each had just been sent `init`.
+    pub fn new(node_count: usize, config: SimConfig) -> Result<Self> {
+        let start = Instant::now();
+        let mut rng = StdRng::seed_from_u64(config.seed);
+        let node_ids: Vec<String> = (1..=node_count).map(|n| format!("n{n}")).collect();
+        let mut nodes = BTreeMap::new();
+        for node_id in &node_ids {
+            let (tx, injected) = mpsc::channel();
+            let timers = Arc::new(Mutex::new(TimerWheel::simulated(start)));
+            let tx = InputSender {
+                tx,
+                depth: Arc::default(),
+            };
+            let injector = Injector {
+                tx,
+                timers: timers.clone(),
+            };
+            let init = Init {
+                node_id: node_id.clone(),
+                node_ids: node_ids.clone(),
+            };
+            let mut state = State::from_init(init, injector)
+                .with_context(|| format!("init of {node_id} failed"))?;
+            if let Some(rpc) = state.rpc() {
+                rpc.reseed(rng.random());
+            }
+            let (tx, sent) = mpsc::channel();
+            let output = Output::new(ChannelWriter::new(tx));
+            let node = SimNode {
+                state,
+                timers,
+                injected,
+                output,
+                sent,
+            };
+            nodes.insert(node_id.clone(), node);
+        }
+
+        let mut sim = Self {
+            nodes,
+            services: HashMap::new(),
+            in_flight: BTreeMap::new(),
+            next_seq: 0,
+            start,
+            now: start,
+            rng,
+            latency: config.latency,
+            nemesis: config.nemesis,
+            next_client_msg_id: 1,
+            client_inbox: Vec::new(),
+            history: History::new(),
+            _payload: PhantomData,
+        };
+        for node_id in node_ids {
+            sim.settle(&node_id)?;
+        }
+        Ok(sim)
+    }
+
+    /// Virtual time since the simulation started.
+    pub fn elapsed(&self) -> Duration {
+        self.now - self.start
+    }
+
+    pub fn node_ids(&self) -> impl Iterator<Item = &String> {
+        self.nodes.keys()
+    }
+
+    /// Every client request sent so far and the replies to them, for the checkers.
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    pub fn nemesis(&self) -> &Nemesis {
+        &self.nemesis
+    }
+
+    pub fn nemesis_mut(&mut self) -> &mut Nemesis {
+        &mut self.nemesis
+    }
+
+    pub fn node(&self, id: &str) -> Option<&State> {
+        self.nodes.get(id).map(|node| &node.state)
+    }
+
+    pub fn node_mut(&mut self, id: &str) -> Option<&mut State> {
+        self.nodes.get_mut(id).map(|node| &mut node.state)
+    }
+
+    /// Answers the requests nodes send to `id` with the payload `handler` returns for each,
+    /// the way Maelstrom's own services such as `lin-kv` or `lin-tso` would.
+    pub fn add_service(
+        &mut self,
+        id: impl Into<String>,
+        handler: impl FnMut(&Message<Value>) -> Value + 'static,
+    ) {
+        self.services.insert(id.into(), Box::new(handler));
+    }
+
+    /// Sends `payload` from `client` to node `dest`, returning the request's `msg_id`.
+    pub fn send(&mut self, client: &str, dest: &str, payload: impl Serialize) -> Result<usize> {
+        if !self.nodes.contains_key(dest) {
+            bail!("no node named {dest:?}");
+        }
+        let id = self.next_client_msg_id;
+        self.next_client_msg_id += 1;
+        let payload = serde_json::to_value(payload).context("serialize client message")?;
+        let msg = Message {
+            src: client.to_string(),
+            dest: dest.to_string(),
+            body: Body::new(Some(id), payload),
+        };
+        self.history.invoke(self.elapsed(), &msg);
+        let line = serde_json::to_string(&msg).context("serialize client message")?;
+        self.enqueue(msg.src, msg.dest, line, Duration::ZERO);
+        Ok(id)
+    }
+
+    /// Sends `payload` from `client` and runs the cluster until `dest` answers it, for at
+    /// most `timeout` of virtual time.
+    pub fn call<Resp: DeserializeOwned>(
+        &mut self,
+        client: &str,
+        dest: &str,
+        payload: impl Serialize,
+        timeout: Duration,
+    ) -> Result<Message<Resp>> {
+        let id = self.send(client, dest, payload)?;
+        let deadline = self.now + timeout;
+        loop {
+            let reply = self
+                .client_inbox
+                .iter()
+                .position(|msg| msg.dest == client && msg.body.reply_to == Some(id));
+            if let Some(reply) = reply {
+                let reply = self.client_inbox.remove(reply);
+                return reply.decode().context("decode reply");
+            }
+            if !self.step_until(deadline)? {
+                return Err(anyhow!("{dest} did not answer msg {id} within {timeout:?}"));
+            }
+        }
+    }
+
+    /// Takes every message nodes have sent to clients so far.
+    pub fn take_client_messages(&mut self) -> Vec<Message<Value>> {
+        std::mem::take(&mut self.client_inbox)
+    }
+
+    /// Runs everything due within the next `duration` of virtual time.
+    pub fn run_for(&mut self, duration: Duration) -> Result<()> {
+        let deadline = self.now + duration;
+        while self.step_until(deadline)? {}
+        self.now = deadline;
+        Ok(())
+    }
+
+    /// Advances the clock to the next delivery or timer and handles everything due then.
+    /// Returns false if nothing is scheduled at all.
+    pub fn step(&mut self) -> Result<bool> {
+        let Some(next) = self.next_event() else {
+            return Ok(false);
+        };
+        self.now = self.now.max(next);
+        let now = self.now;
+
+        let ids: Vec<String> = self.nodes.keys().cloned().collect();
+        for id in ids {
+            let node = self.nodes.get_mut(&id).expect("node ids are fixed");
+            if Self::wakeup(node).is_some_and(|wakeup| wakeup <= now) {
+                advance(&mut node.state, &node.timers, now, &mut node.output)?;
+                self.settle(&id)?;
+            }
+        }
+
+        while let Some(entry) = self.in_flight.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let Envelope { src, dest, line } = entry.remove();
+            if self.nodes.contains_key(&src) && self.nemesis.blocks(&src, &dest) {
+                continue;
+            }
+            let node = self.nodes.get_mut(&dest).expect("only nodes are sent to");
+            advance(&mut node.state, &node.timers, now, &mut node.output)?;
+            handle_line(&mut node.state, &line, &mut node.output)?;
+            self.settle(&dest)?;
+        }
+        Ok(true)
+    }
+
+    fn step_until(&mut self, deadline: Instant) -> Result<bool> {
+        match self.next_event() {
+            Some(next) if next <= deadline => self.step(),
+            _ => Ok(false),
+        }
+    }
+
+    fn next_event(&mut self) -> Option<Instant> {
+        let deliveries = self.in_flight.keys().next().map(|(at, _)| *at);
+        let wakeups = self.nodes.values_mut().filter_map(Self::wakeup);
+        deliveries.into_iter().chain(wakeups).min()
+    }
+
+    fn wakeup(node: &mut SimNode<State, InjectedPayload>) -> Option<Instant> {
+        let rpc = node.state.rpc().and_then(|rpc| rpc.next_wakeup());
+        let timers = node
+            .timers
+            .lock()
+            .expect("timer lock poisoned")
+            .next_wakeup();
+        rpc.into_iter().chain(timers).min()
+    }
+
+    /// Handles whatever the node injected into itself and routes what it sent.
+    fn settle(&mut self, id: &str) -> Result<()> {
+        let node = self.nodes.get_mut(id).expect("node ids are fixed");
+        while let Ok(input) = node.injected.try_recv() {
+            if let Input::Injected(payload) = input {
+                node.state
+                    .process_message(Event::Injected(payload), &mut node.output)
+                    .context("process message failed")?;
+            }
+        }
+        node.output.flush_tick()?;
+        let sent: Vec<String> = node.sent.try_iter().collect();
+        for line in sent {
+            let msg: Message<Value> =
+                serde_json::from_str(&line).with_context(|| format!("{id} sent {line:?}"))?;
+            if self.nodes.contains_key(&msg.dest) {
+                for delay in self.nemesis.copies(&mut self.rng) {
+                    self.enqueue(msg.src.clone(), msg.dest.clone(), line.clone(), delay);
+                }
+            } else if let Some(service) = self.services.get_mut(&msg.dest) {
+                let body = Body {
+                    id: None,
+                    reply_to: msg.body.id,
+                    payload: service(&msg),
+                };
+                let reply = Message::new(msg.dest, msg.src, body);
+                let line = serde_json::to_string(&reply).context("serialize service reply")?;
+                self.enqueue(reply.src, reply.dest, line, Duration::ZERO);
+            } else {
+                self.history.complete(self.elapsed(), &msg);
+                self.client_inbox.push(msg);
+            }
+        }
+        Ok(())
+    }
+
+    fn enqueue(&mut self, src: String, dest: String, line: String, delay: Duration) {
+        let at = self.now + self.latency.sample(&mut self.rng) + delay;
+        self.in_flight
+            .insert((at, self.next_seq), Envelope { src, dest, line });
+        self.next_seq += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Broadcaster, Payload};
+
+    /// The order eight messages first sent to an isolated `n1` reached `n2` in, once the
+    /// partition healed. With a fixed latency, all their retries fall due at the same instant.
+    fn arrivals(seed: u64) -> Vec<u64> {
+        let config = SimConfig {
+            latency: Latency::Fixed(Duration::from_millis(5)),
+            seed,
+            ..SimConfig::default()
+        };
+        let mut sim: Simulation<Broadcaster, Payload> = Simulation::new(2, config).unwrap();
+        sim.nemesis_mut().isolate("n1");
+        for message in 0..8 {
+            sim.send("c1", "n1", Payload::Broadcast { message }).unwrap();
+        }
+        sim.run_for(Duration::from_millis(250)).unwrap();
+        sim.nemesis_mut().heal();
+        sim.run_for(Duration::from_secs(1)).unwrap();
+        sim.node("n2").unwrap().messages.clone()
+    }
+
+    #[test]
+    fn same_seed_gives_same_interleaving() {
+        let first = arrivals(0);
+        assert_eq!(first.len(), 8);
+        for _ in 0..5 {
+            assert_eq!(arrivals(0), first);
+        }
+    }
+
+    #[test]
+    fn call_runs_until_the_reply() {
+        let mut sim: Simulation<Broadcaster, Payload> =
+            Simulation::new(3, SimConfig::default()).unwrap();
+        let reply: Message<Payload> = sim
+            .call("c1", "n1", Payload::Broadcast { message: 7 }, Duration::from_secs(1))
+            .unwrap();
+        assert!(matches!(reply.body.payload, Payload::BroadcastOk));
+        assert_eq!(sim.history().operations().len(), 1);
+
+        sim.run_for(Duration::from_millis(100)).unwrap();
+        for node in ["n2", "n3"] {
+            assert_eq!(sim.node(node).unwrap().messages, [7]);
+        }
+    }
+}
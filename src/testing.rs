@@ -0,0 +1,96 @@
+//! A small node for the simulator and nemesis tests.
+
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::{
+    payload, CallOptions, Event, Identity, Init, Injector, Message, Node, Output, RetryPolicy, Rpc,
+};
+
+#[payload]
+#[derive(Debug, Clone)]
+pub(crate) enum Payload {
+    Broadcast { message: u64 },
+    BroadcastOk,
+    Share { message: u64 },
+    ShareOk,
+    Read,
+    ReadOk { messages: Vec<u64> },
+}
+
+/// Broadcast by direct fan-out: the node a client tells shares the message with every peer,
+/// retrying each every 100ms until it is acknowledged. Messages are kept in arrival order.
+pub(crate) struct Broadcaster {
+    id: String,
+    peers: Vec<String>,
+    msg_id: usize,
+    pub(crate) messages: Vec<u64>,
+    rpc: Rpc<Self>,
+}
+
+impl Identity for Broadcaster {
+    fn next_msg_id(&mut self) -> usize {
+        let out = self.msg_id;
+        self.msg_id += 1;
+        out
+    }
+
+    fn node_id(&self) -> String {
+        self.id.clone()
+    }
+}
+
+impl Node<Payload> for Broadcaster {
+    fn from_init(init: Init, _tx: Injector<()>) -> Result<Self> {
+        let peers = init
+            .node_ids
+            .into_iter()
+            .filter(|id| *id != init.node_id)
+            .collect();
+        Ok(Self {
+            id: init.node_id,
+            peers,
+            msg_id: 1,
+            messages: Vec::new(),
+            rpc: Rpc::new(),
+        })
+    }
+
+    fn rpc(&mut self) -> Option<&mut Rpc<Self>> {
+        Some(&mut self.rpc)
+    }
+
+    fn process_message(&mut self, event: Event<Payload>, output: &mut Output) -> Result<()> {
+        let Event::Message(msg) = event else {
+            return Ok(());
+        };
+        match msg.body.payload.clone() {
+            Payload::Broadcast { message } => {
+                self.messages.push(message);
+                for peer in self.peers.clone() {
+                    let request = self.request(peer, Payload::Share { message });
+                    let retry = RetryPolicy::fixed(Duration::from_millis(100));
+                    self.rpc.call_with(
+                        &request,
+                        &mut *output,
+                        CallOptions::default().with_retry(retry),
+                        |_: &mut Self, _: Result<Message<Payload>, _>, _| Ok(()),
+                    )?;
+                }
+                self.reply(msg, Payload::BroadcastOk).send(output)
+            }
+            Payload::Share { message } => {
+                if !self.messages.contains(&message) {
+                    self.messages.push(message);
+                }
+                self.reply(msg, Payload::ShareOk).send(output)
+            }
+            Payload::Read => {
+                let messages = self.messages.clone();
+                self.reply(msg, Payload::ReadOk { messages }).send(output)
+            }
+            Payload::BroadcastOk | Payload::ShareOk | Payload::ReadOk { .. } => Ok(()),
+        }
+    }
+}
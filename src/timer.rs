@@ -0,0 +1,190 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerId(u64);
+
+enum Repeat<InjectedPayload> {
+    Once,
+    Every {
+        period: Duration,
+        clone: fn(&InjectedPayload) -> InjectedPayload,
+    },
+}
+
+struct Timer<InjectedPayload> {
+    id: TimerId,
+    payload: InjectedPayload,
+    repeat: Repeat<InjectedPayload>,
+}
+
+/// One-shot and periodic timers owned by `main_loop`, which sleeps until the earliest one is
+/// due and delivers its payload to the node as `Event::Injected`.
+pub(crate) struct TimerWheel<InjectedPayload> {
+    now: Instant,
+    /// Whether `now` follows the wall clock, rather than only moving when `fire` is called.
+    realtime: bool,
+    next_id: u64,
+    queue: BTreeMap<(Instant, TimerId), Timer<InjectedPayload>>,
+    deadlines: HashMap<TimerId, Instant>,
+}
+
+impl<InjectedPayload> TimerWheel<InjectedPayload> {
+    pub(crate) fn new(now: Instant) -> Self {
+        Self {
+            now,
+            realtime: true,
+            next_id: 0,
+            queue: BTreeMap::new(),
+            deadlines: HashMap::new(),
+        }
+    }
+
+    /// A wheel on a virtual clock that only `fire` moves, for the simulator.
+    pub(crate) fn simulated(start: Instant) -> Self {
+        Self {
+            realtime: false,
+            ..Self::new(start)
+        }
+    }
+
+    pub(crate) fn schedule_once(&mut self, after: Duration, payload: InjectedPayload) -> TimerId {
+        self.insert(after, payload, Repeat::Once)
+    }
+
+    pub(crate) fn schedule_every(&mut self, period: Duration, payload: InjectedPayload) -> TimerId
+    where
+        InjectedPayload: Clone,
+    {
+        let clone = InjectedPayload::clone;
+        self.insert(period, payload, Repeat::Every { period, clone })
+    }
+
+    fn insert(
+        &mut self,
+        after: Duration,
+        payload: InjectedPayload,
+        repeat: Repeat<InjectedPayload>,
+    ) -> TimerId {
+        // The loop only fires timers when it wakes, so a handler on another thread may be
+        // scheduling long after `now` was last moved.
+        if self.realtime {
+            self.now = self.now.max(Instant::now());
+        }
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+        let deadline = self.now + after;
+        self.queue.insert((deadline, id), Timer { id, payload, repeat });
+        self.deadlines.insert(id, deadline);
+        id
+    }
+
+    pub(crate) fn cancel(&mut self, id: TimerId) -> bool {
+        let Some(deadline) = self.deadlines.remove(&id) else {
+            return false;
+        };
+        self.queue.remove(&(deadline, id)).is_some()
+    }
+
+    pub(crate) fn next_wakeup(&self) -> Option<Instant> {
+        self.queue.keys().next().map(|(deadline, _)| *deadline)
+    }
+
+    /// Pops every timer due at `now`, re-arming the periodic ones.
+    pub(crate) fn fire(&mut self, now: Instant) -> Vec<InjectedPayload> {
+        self.now = now;
+        let mut fired = Vec::new();
+        while let Some(entry) = self.queue.first_entry() {
+            let (deadline, _) = *entry.key();
+            if deadline > now {
+                break;
+            }
+            let timer = entry.remove();
+            match timer.repeat {
+                Repeat::Once => {
+                    self.deadlines.remove(&timer.id);
+                    fired.push(timer.payload);
+                }
+                Repeat::Every { period, clone } => {
+                    // Skip ticks we slept through rather than firing them back to back.
+                    let next = if deadline + period > now {
+                        deadline + period
+                    } else {
+                        now + period
+                    };
+                    fired.push(clone(&timer.payload));
+                    self.deadlines.insert(timer.id, next);
+                    self.queue.insert((next, timer.id), timer);
+                }
+            }
+        }
+        fired
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.queue.clear();
+        self.deadlines.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn once_fires_when_due_and_only_then() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::simulated(start);
+        wheel.schedule_once(10 * MS, 1);
+        assert_eq!(wheel.next_wakeup(), Some(start + 10 * MS));
+        assert!(wheel.fire(start + 9 * MS).is_empty());
+        assert_eq!(wheel.fire(start + 10 * MS), [1]);
+        assert!(wheel.fire(start + 100 * MS).is_empty());
+        assert_eq!(wheel.next_wakeup(), None);
+    }
+
+    #[test]
+    fn every_rearms_and_skips_ticks_it_slept_through() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::simulated(start);
+        wheel.schedule_every(10 * MS, 'a');
+        assert_eq!(wheel.fire(start + 10 * MS), ['a']);
+        assert_eq!(wheel.fire(start + 20 * MS), ['a']);
+        assert_eq!(wheel.fire(start + 55 * MS), ['a']);
+        assert_eq!(wheel.next_wakeup(), Some(start + 65 * MS));
+    }
+
+    #[test]
+    fn cancelled_and_cleared_timers_never_fire() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::simulated(start);
+        let once = wheel.schedule_once(10 * MS, 1);
+        let every = wheel.schedule_every(10 * MS, 2);
+        assert!(wheel.cancel(once));
+        assert!(!wheel.cancel(once));
+        assert_eq!(wheel.fire(start + 10 * MS), [2]);
+        assert!(wheel.cancel(every));
+        assert_eq!(wheel.next_wakeup(), None);
+
+        wheel.schedule_every(10 * MS, 3);
+        wheel.clear();
+        assert_eq!(wheel.next_wakeup(), None);
+        assert!(wheel.fire(start + 100 * MS).is_empty());
+    }
+
+    #[test]
+    fn realtime_timers_count_from_when_they_are_scheduled() {
+        // As if the loop last fired 300ms ago and has been asleep since.
+        let mut wheel = TimerWheel::new(Instant::now() - 300 * MS);
+        wheel.schedule_once(250 * MS, ());
+        assert!(wheel.next_wakeup().unwrap() > Instant::now() + 200 * MS);
+
+        let mut simulated = TimerWheel::simulated(Instant::now() - 300 * MS);
+        simulated.schedule_once(250 * MS, ());
+        assert!(simulated.next_wakeup().unwrap() < Instant::now());
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    io::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+
+use crate::env_flag;
+
+/// Set to anything but `0` or `false` to have `main_loop` trace its traffic to stderr.
+pub const TRACE_ENV: &str = "MAELSTROM_TRACE";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Direction {
+    Recv,
+    Send,
+    Inject,
+}
+
+impl Direction {
+    fn as_str(self) -> &'static str {
+        match self {
+            Direction::Recv => "recv",
+            Direction::Send => "send",
+            Direction::Inject => "inject",
+        }
+    }
+}
+
+/// Writes one JSON line per message or injected event to stderr, which Maelstrom keeps as the
+/// node's log. `seq` orders the lines of one node even when timestamps tie.
+pub(crate) struct Tracer {
+    node_id: String,
+    seq: u64,
+}
+
+impl Tracer {
+    /// A tracer for `node_id` if `TRACE_ENV` asks for one.
+    pub(crate) fn from_env(node_id: &str) -> Option<Self> {
+        env_flag(TRACE_ENV).then(|| Self {
+            node_id: node_id.to_string(),
+            seq: 0,
+        })
+    }
+
+    /// Traces an input or output line, as parsed JSON when it parses and as a string if not.
+    pub(crate) fn message(&mut self, direction: Direction, message: &Value) {
+        self.record(direction, json!({ "message": message }));
+    }
+
+    /// Traces an injected event; `timer` tells timer deliveries from `Injector::inject`.
+    pub(crate) fn injected(&mut self, timer: bool) {
+        self.record(Direction::Inject, json!({ "timer": timer }));
+    }
+
+    fn record(&mut self, direction: Direction, fields: Value) {
+        let line = self.entry(direction, fields);
+        // Tracing must never take the node down, so a closed stderr is ignored.
+        let _ = writeln!(std::io::stderr().lock(), "{line}");
+    }
+
+    /// Stamps `fields` with the next sequence number, the time, the node and the direction.
+    fn entry(&mut self, direction: Direction, mut fields: Value) -> Value {
+        self.seq += 1;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        fields["seq"] = self.seq.into();
+        fields["timestamp_us"] = timestamp.into();
+        fields["node"] = self.node_id.clone().into();
+        fields["direction"] = direction.as_str().into();
+        fields
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracer() -> Tracer {
+        Tracer {
+            node_id: "n1".to_string(),
+            seq: 0,
+        }
+    }
+
+    #[test]
+    fn entries_carry_the_node_direction_and_time() {
+        let message = json!({ "src": "c1", "dest": "n1", "body": { "type": "read" } });
+        let entry = tracer().entry(Direction::Recv, json!({ "message": message }));
+        assert_eq!(entry["node"], "n1");
+        assert_eq!(entry["direction"], "recv");
+        assert_eq!(entry["message"], message);
+        assert_eq!(entry["seq"], 1);
+        assert!(entry["timestamp_us"].as_u64().unwrap() > 0);
+    }
+
+    #[test]
+    fn sequence_numbers_increase_across_directions() {
+        let mut tracer = tracer();
+        let directions = [Direction::Recv, Direction::Inject, Direction::Send];
+        let entries: Vec<_> = directions
+            .into_iter()
+            .map(|direction| tracer.entry(direction, json!({})))
+            .collect();
+        let seqs: Vec<_> = entries.iter().map(|entry| entry["seq"].as_u64()).collect();
+        assert_eq!(seqs, [Some(1), Some(2), Some(3)]);
+        let directions: Vec<_> = entries.iter().map(|entry| &entry["direction"]).collect();
+        assert_eq!(directions, ["recv", "inject", "send"]);
+        assert!(entries[0]["timestamp_us"].as_u64() <= entries[2]["timestamp_us"].as_u64());
+    }
+}
@@ -0,0 +1,362 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Lines, Stdin, Stdout, Write},
+    path::Path,
+    sync::mpsc::{self, Receiver, Sender},
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use serde_json::{json, Value};
+
+use crate::{
+    metrics::Metrics,
+    trace::{Direction, Tracer},
+};
+
+/// Where a node reads its input lines from and writes its messages to.
+///
+/// `main_loop_with` reads the init line itself and then moves `Lines` to its reader thread,
+/// so lines must be `Send`; `Writer` stays on the loop's thread.
+pub trait Transport {
+    type Lines: Iterator<Item = io::Result<String>> + Send + 'static;
+    type Writer: Write + 'static;
+
+    fn open(self) -> io::Result<(Self::Lines, Self::Writer)>;
+}
+
+/// The process's stdin and stdout, as Maelstrom runs nodes.
+pub struct Stdio;
+
+impl Transport for Stdio {
+    type Lines = Lines<BufReader<Stdin>>;
+    type Writer = Stdout;
+
+    fn open(self) -> io::Result<(Self::Lines, Self::Writer)> {
+        Ok((BufReader::new(io::stdin()).lines(), io::stdout()))
+    }
+}
+
+/// Any reader and writer pair, such as a recorded input file to replay or a socket.
+pub struct Streams<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R, W> Streams<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Self { reader, writer }
+    }
+}
+
+impl Streams<BufReader<File>, Stdout> {
+    /// Feeds the lines of the file at `path` to the node, writing its output to stdout.
+    pub fn replay(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(BufReader::new(File::open(path)?), io::stdout()))
+    }
+}
+
+#[cfg(unix)]
+impl Streams<BufReader<UnixStream>, UnixStream> {
+    /// Connects to the Unix socket at `path` and talks over it in both directions.
+    pub fn unix(path: impl AsRef<Path>) -> io::Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        Ok(Self::new(BufReader::new(stream.try_clone()?), stream))
+    }
+}
+
+impl<R, W> Transport for Streams<R, W>
+where
+    R: BufRead + Send + 'static,
+    W: Write + 'static,
+{
+    type Lines = Lines<R>;
+    type Writer = W;
+
+    fn open(self) -> io::Result<(Self::Lines, Self::Writer)> {
+        Ok((self.reader.lines(), self.writer))
+    }
+}
+
+/// An in-memory transport: the node reads lines from one channel and sends every line it
+/// writes, without the trailing newline, down another. Input ends when all senders are gone.
+pub struct Channel {
+    input: Receiver<String>,
+    output: Sender<String>,
+}
+
+impl Channel {
+    pub fn new(input: Receiver<String>, output: Sender<String>) -> Self {
+        Self { input, output }
+    }
+
+    /// A transport along with the sender feeding it and the receiver it writes to.
+    pub fn pair() -> (Self, Sender<String>, Receiver<String>) {
+        let (input_tx, input_rx) = mpsc::channel();
+        let (output_tx, output_rx) = mpsc::channel();
+        (Self::new(input_rx, output_tx), input_tx, output_rx)
+    }
+}
+
+pub struct ChannelLines(Receiver<String>);
+
+impl Iterator for ChannelLines {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.recv().ok().map(Ok)
+    }
+}
+
+pub struct ChannelWriter {
+    tx: Sender<String>,
+    line: Vec<u8>,
+}
+
+impl ChannelWriter {
+    pub(crate) fn new(tx: Sender<String>) -> Self {
+        Self {
+            tx,
+            line: Vec::new(),
+        }
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            if byte != b'\n' {
+                self.line.push(byte);
+                continue;
+            }
+            let line = String::from_utf8(std::mem::take(&mut self.line))
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            self.tx.send(line).map_err(|_| {
+                io::Error::new(io::ErrorKind::BrokenPipe, "channel receiver dropped")
+            })?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Channel {
+    type Lines = ChannelLines;
+    type Writer = ChannelWriter;
+
+    fn open(self) -> io::Result<(Self::Lines, Self::Writer)> {
+        Ok((ChannelLines(self.input), ChannelWriter::new(self.output)))
+    }
+}
+
+/// The handle nodes write their messages to, whatever transport is underneath. Messages are
+/// buffered and flushed through to the transport together once per turn of the main loop, so
+/// a node sending to every neighbour at once makes one write rather than one per message.
+///
+/// When tracing or metrics are on, it also watches the lines going in and out of the node.
+pub struct Output {
+    inner: BufWriter<Box<dyn Write>>,
+    tracer: Option<Tracer>,
+    metrics: Option<Metrics>,
+    line: Vec<u8>,
+    /// Bytes written since the last `flush_tick`.
+    pending: usize,
+    /// Bytes the last `flush_tick` that had anything to flush wrote.
+    last_tick: usize,
+}
+
+impl Output {
+    pub fn new(writer: impl Write + 'static) -> Self {
+        Self {
+            inner: BufWriter::new(Box::new(writer)),
+            tracer: None,
+            metrics: None,
+            line: Vec::new(),
+            pending: 0,
+            last_tick: 0,
+        }
+    }
+
+    /// Flushes what the node wrote since the last tick, recording how much it was.
+    pub(crate) fn flush_tick(&mut self) -> io::Result<()> {
+        if self.pending == 0 {
+            return Ok(());
+        }
+        self.inner.flush()?;
+        if let Some(metrics) = &mut self.metrics {
+            metrics.record_tick_bytes(self.pending);
+        }
+        self.last_tick = std::mem::take(&mut self.pending);
+        Ok(())
+    }
+
+    /// How many bytes the last turn of the main loop that sent anything wrote, whether or not
+    /// metrics are on.
+    pub fn last_tick_bytes(&self) -> usize {
+        self.last_tick
+    }
+
+    /// The metrics collected so far, if `METRICS_ENV` turned them on.
+    pub fn metrics(&self) -> Option<&Metrics> {
+        self.metrics.as_ref()
+    }
+
+    pub(crate) fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub(crate) fn set_metrics(&mut self, metrics: Option<Metrics>) {
+        self.metrics = metrics;
+    }
+
+    fn observe(&mut self, direction: Direction, line: &str) {
+        if self.tracer.is_none() && self.metrics.is_none() {
+            return;
+        }
+        let msg = serde_json::from_str(line).unwrap_or_else(|_| Value::from(line));
+        if let Some(tracer) = &mut self.tracer {
+            tracer.message(direction, &msg);
+        }
+        if let Some(metrics) = &mut self.metrics {
+            match direction {
+                Direction::Recv => metrics.record_received(&msg),
+                _ => metrics.record_sent(&msg),
+            }
+        }
+    }
+
+    pub(crate) fn observe_recv(&mut self, line: &str) {
+        self.observe(Direction::Recv, line);
+    }
+
+    pub(crate) fn trace_injected(&mut self, timer: bool) {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.injected(timer);
+        }
+    }
+
+    pub(crate) fn record_rpc_latency(&mut self, latency: Duration) {
+        if let Some(metrics) = &mut self.metrics {
+            metrics.record_rpc_latency(latency);
+        }
+    }
+
+    pub(crate) fn record_queue_depth(&mut self, depth: usize) {
+        if let Some(metrics) = &mut self.metrics {
+            metrics.record_queue_depth(depth);
+        }
+    }
+
+    /// Writes the metrics summary to stderr as one JSON line, if metrics are on.
+    pub(crate) fn dump_metrics(&self, node_id: &str) {
+        if let Some(metrics) = &self.metrics {
+            let summary = json!({ "node": node_id, "metrics": metrics.summary() });
+            let _ = writeln!(io::stderr().lock(), "{summary}");
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.pending += written;
+        if self.tracer.is_some() || self.metrics.is_some() {
+            for &byte in &buf[..written] {
+                if byte != b'\n' {
+                    self.line.push(byte);
+                    continue;
+                }
+                let line = String::from_utf8_lossy(&std::mem::take(&mut self.line)).into_owned();
+                self.observe(Direction::Send, &line);
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, process};
+
+    use super::*;
+
+    /// A path under the temp dir no other test process will use.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("maelstrom-node-{}-{name}", process::id()))
+    }
+
+    fn lines(lines: impl Iterator<Item = io::Result<String>>) -> Vec<String> {
+        lines.map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn channel_writer_sends_whole_lines_across_partial_writes() {
+        let (tx, rx) = mpsc::channel();
+        let mut writer = ChannelWriter::new(tx);
+        writer.write_all(br#"{"a""#).unwrap();
+        assert!(rx.try_recv().is_err());
+        writer.write_all(b":1}\n{\"b\":2}\n{\"c\"").unwrap();
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            [r#"{"a":1}"#, r#"{"b":2}"#]
+        );
+        writer.write_all(b":3}\n").unwrap();
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [r#"{"c":3}"#]);
+    }
+
+    #[test]
+    fn channel_writer_rejects_bad_utf8_and_a_dropped_receiver() {
+        let (tx, rx) = mpsc::channel();
+        let mut writer = ChannelWriter::new(tx);
+        let err = writer.write(b"\xff\n").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        drop(rx);
+        let err = writer.write(b"{}\n").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn replay_reads_the_file_line_by_line() {
+        let path = temp_path("replay");
+        fs::write(&path, "{\"a\":1}\n{\"b\":2}\n").unwrap();
+        let (input, _) = Streams::replay(&path).unwrap().open().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(lines(input), [r#"{"a":1}"#, r#"{"b":2}"#]);
+
+        assert!(Streams::replay(temp_path("missing")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_talks_over_the_socket_both_ways() {
+        use std::os::unix::net::UnixListener;
+
+        let path = temp_path("unix");
+        let listener = UnixListener::bind(&path).unwrap();
+        let (input, mut writer) = Streams::unix(&path).unwrap().open().unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        writeln!(writer, r#"{{"from":"node"}}"#).unwrap();
+        let mut sent = String::new();
+        BufReader::new(peer.try_clone().unwrap())
+            .read_line(&mut sent)
+            .unwrap();
+        assert_eq!(sent, "{\"from\":\"node\"}\n");
+
+        writeln!(peer, r#"{{"from":"peer"}}"#).unwrap();
+        drop(peer);
+        drop(writer);
+        assert_eq!(lines(input), [r#"{"from":"peer"}"#]);
+    }
+}
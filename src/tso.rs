@@ -0,0 +1,162 @@
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{CallOptions, Message, Node, Output, RpcError};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum TsoPayload {
+    Ts {},
+    TsOk { ts: u64 },
+}
+
+/// Client for Maelstrom's `lin-tso` service, which hands out strictly increasing timestamps.
+/// Like `Kv`, it needs the node to expose its `Rpc` table.
+#[derive(Debug, Clone, Default)]
+pub struct Tso {
+    options: CallOptions,
+}
+
+impl Tso {
+    pub const NODE_ID: &'static str = "lin-tso";
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_options(mut self, options: CallOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn ts<State, Payload, InjectedPayload, F>(
+        &self,
+        node: &mut State,
+        output: &mut Output,
+        handler: F,
+    ) -> Result<()>
+    where
+        State: Node<Payload, InjectedPayload>,
+        F: FnOnce(&mut State, Result<u64, RpcError>, &mut Output) -> Result<()> + Send + 'static,
+    {
+        let msg = node.request(Self::NODE_ID.to_string(), TsoPayload::Ts {});
+        let rpc = node.rpc().context("tso client needs the node to expose its rpc table")?;
+        rpc.call_with(
+            &msg,
+            output,
+            self.options.clone(),
+            |node, reply: Result<Message<TsoPayload>, RpcError>, output| {
+                let ts = reply.and_then(|msg| match msg.body.payload {
+                    TsoPayload::TsOk { ts } => Ok(ts),
+                    other => Err(RpcError::Malformed(format!("unexpected tso reply {other:?}"))),
+                });
+                handler(node, ts, output)
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{payload, ErrorCode, Event, Identity, Init, Injector, Rpc, SimConfig, Simulation};
+
+    #[payload]
+    #[derive(Debug, Clone)]
+    enum Payload {
+        Stamp,
+    }
+
+    /// Asks `lin-tso` for a timestamp on every `stamp` and keeps what it got back.
+    struct Stamper {
+        id: String,
+        msg_id: usize,
+        rpc: Rpc<Self>,
+        stamps: Vec<Result<u64, RpcError>>,
+    }
+
+    impl Identity for Stamper {
+        fn next_msg_id(&mut self) -> usize {
+            let out = self.msg_id;
+            self.msg_id += 1;
+            out
+        }
+
+        fn node_id(&self) -> String {
+            self.id.clone()
+        }
+    }
+
+    impl Node<Payload> for Stamper {
+        fn from_init(init: Init, _tx: Injector<()>) -> Result<Self> {
+            Ok(Self {
+                id: init.node_id,
+                msg_id: 1,
+                rpc: Rpc::new(),
+                stamps: Vec::new(),
+            })
+        }
+
+        fn rpc(&mut self) -> Option<&mut Rpc<Self>> {
+            Some(&mut self.rpc)
+        }
+
+        fn process_message(&mut self, event: Event<Payload>, output: &mut Output) -> Result<()> {
+            let Event::Message(_) = event else {
+                return Ok(());
+            };
+            Tso::new().ts(self, output, |node: &mut Self, ts, _| {
+                node.stamps.push(ts);
+                Ok(())
+            })
+        }
+    }
+
+    /// A one-node cluster whose `lin-tso` answers the `n`th request with `replies(n)`.
+    fn sim(mut replies: impl FnMut(u64) -> Value + 'static) -> Simulation<Stamper, Payload> {
+        let mut sim = Simulation::new(1, SimConfig::default()).unwrap();
+        let mut n = 0;
+        sim.add_service(Tso::NODE_ID, move |msg: &Message<Value>| {
+            assert_eq!(msg.body.payload, json!({ "type": "ts" }));
+            n += 1;
+            replies(n)
+        });
+        sim
+    }
+
+    /// Has `n1` ask for a timestamp and returns what it got back.
+    fn stamp(sim: &mut Simulation<Stamper, Payload>) -> Result<u64, RpcError> {
+        sim.send("c1", "n1", Payload::Stamp).unwrap();
+        sim.run_for(Duration::from_millis(100)).unwrap();
+        let stamps = &sim.node("n1").unwrap().stamps;
+        stamps.last().cloned().expect("the ts call should complete")
+    }
+
+    #[test]
+    fn timestamps_are_decoded() {
+        let mut sim = sim(|n| json!({ "type": "ts_ok", "ts": n * 10 }));
+        assert_eq!(stamp(&mut sim), Ok(10));
+        assert_eq!(stamp(&mut sim), Ok(20));
+    }
+
+    #[test]
+    fn errors_and_unexpected_replies_are_passed_on() {
+        let mut sim = sim(|n| match n {
+            1 => json!({ "type": "error", "code": 11, "text": "try again" }),
+            _ => json!({ "type": "ts" }),
+        });
+        match stamp(&mut sim) {
+            Err(RpcError::Remote(error)) => {
+                assert_eq!(error.code, ErrorCode::TemporarilyUnavailable);
+            }
+            other => panic!("expected a remote error, got {other:?}"),
+        }
+        assert!(matches!(stamp(&mut sim), Err(RpcError::Malformed(_))));
+    }
+}